@@ -0,0 +1,163 @@
+//! Settings shared by tweak-indexer and tweak-service, read from a TOML file.
+//!
+//! Every field has a default so a file only needs the settings it changes. The
+//! binaries apply their CLI flags and environment variables on top.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::network::Network;
+
+/// Read when no `--config` is given and it exists
+pub const DEFAULT_CONFIG_PATH: &str = "tweak.toml";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: Network,
+    pub db: String,
+    pub log_dir: PathBuf,
+    pub rpc: RpcSettings,
+    pub indexer: IndexerSettings,
+    pub service: ServiceSettings,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcSettings {
+    // defaults to localhost on the network's RPC port
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    // defaults to the network's .cookie under ~/.bitcoin, unused when user and password are set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cookie: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    // seconds
+    pub timeout: u64,
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexerSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocks_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub esplora_url: Option<String>,
+    // seconds
+    pub poll_interval: u64,
+    pub reorg_depth: u32,
+    // defaults to the number of CPUs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceSettings {
+    pub bind: IpAddr,
+    pub port: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            network: Network::Mainnet,
+            db: String::from("blocks.db"),
+            log_dir: PathBuf::from("logs"),
+            rpc: RpcSettings::default(),
+            indexer: IndexerSettings::default(),
+            service: ServiceSettings::default(),
+        }
+    }
+}
+
+impl Default for RpcSettings {
+    fn default() -> Self {
+        Self { url: None, cookie: None, user: None, password: None, timeout: 60, retries: 3 }
+    }
+}
+
+impl Default for IndexerSettings {
+    fn default() -> Self {
+        Self { blocks_dir: None, esplora_url: None, poll_interval: 300, reorg_depth: 100, jobs: None }
+    }
+}
+
+impl Default for ServiceSettings {
+    fn default() -> Self {
+        Self { bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED), port: 3030 }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "Unable to read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "Invalid config {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Config {
+    /// Load `path`, or `tweak.toml` if there is one when no path is given, else the defaults
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(Self::default()),
+        };
+        let text = std::fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+        toml::from_str(&text).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))
+    }
+
+    /// The configuration as TOML, for `--print-config`. The RPC password is masked
+    pub fn to_toml(&self) -> String {
+        let mut shown = self.clone();
+        if shown.rpc.password.is_some() {
+            shown.rpc.password = Some(String::from("********"));
+        }
+        toml::to_string(&shown).expect("config serializes to TOML")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_partial_config() {
+        let config: Config = toml::from_str(
+            "network = \"signet\"\n\
+             [rpc]\n\
+             user = \"alice\"\n\
+             password = \"secret\"\n\
+             [service]\n\
+             port = 8080\n",
+        ).unwrap();
+        assert_eq!(config.network, Network::Signet);
+        assert_eq!(config.db, "blocks.db");
+        assert_eq!(config.rpc.timeout, 60);
+        assert_eq!(config.service.port, 8080);
+
+        let shown = config.to_toml();
+        assert!(!shown.contains("secret"));
+        let reparsed: Config = toml::from_str(&shown).unwrap();
+        assert_eq!(reparsed.network, Network::Signet);
+        assert_eq!(reparsed.rpc.user.as_deref(), Some("alice"));
+
+        assert!(toml::from_str::<Config>("netwrok = \"signet\"").is_err());
+    }
+}
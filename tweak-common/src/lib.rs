@@ -0,0 +1,6 @@
+//! Code shared by tweak-indexer and tweak-service
+
+pub mod config;
+pub mod network;
+pub mod schema;
+pub mod wire;
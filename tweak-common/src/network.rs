@@ -0,0 +1,102 @@
+//! The Bitcoin network a `blocks.db` was indexed from.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    /// First block that can hold a taproot spend, where indexing starts by default
+    pub fn taproot_start_height(self) -> u32 {
+        match self {
+            Network::Mainnet => 709632,
+            Network::Testnet => 2011968,
+            // taproot is active from genesis, which has nothing to spend
+            Network::Testnet4 | Network::Signet | Network::Regtest => 1,
+        }
+    }
+
+    /// Bitcoin Core's default RPC port
+    pub fn rpc_port(self) -> u16 {
+        match self {
+            Network::Mainnet => 8332,
+            Network::Testnet => 18332,
+            Network::Testnet4 => 48332,
+            Network::Signet => 38332,
+            Network::Regtest => 18443,
+        }
+    }
+
+    /// Subdirectory of the Bitcoin Core datadir holding this network's files, if any
+    pub fn data_subdir(self) -> Option<&'static str> {
+        match self {
+            Network::Mainnet => None,
+            Network::Testnet => Some("testnet3"),
+            Network::Testnet4 => Some("testnet4"),
+            Network::Signet => Some("signet"),
+            Network::Regtest => Some("regtest"),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Testnet4 => "testnet4",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Written as its name in config files
+impl serde::Serialize for Network {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Network {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" | "main" | "bitcoin" => Ok(Network::Mainnet),
+            "testnet" | "testnet3" | "test" => Ok(Network::Testnet),
+            "testnet4" => Ok(Network::Testnet4),
+            "signet" => Ok(Network::Signet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network {}, expected mainnet, testnet, testnet4, signet or regtest", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_names() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Testnet4, Network::Signet, Network::Regtest] {
+            assert_eq!(network.to_string().parse::<Network>().unwrap(), network);
+        }
+        assert_eq!("testnet3".parse::<Network>().unwrap(), Network::Testnet);
+        assert!("liquid".parse::<Network>().is_err());
+    }
+}
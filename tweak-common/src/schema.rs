@@ -0,0 +1,323 @@
+//! Versioned schema for `blocks.db`.
+//!
+//! The version lives in SQLite's `user_version` pragma and every entry in
+//! `MIGRATIONS` moves the database up by one. Databases created before
+//! versioning existed report version 0, so the early migrations are written
+//! to tolerate tables and columns that are already there.
+
+use rusqlite::{Connection, OptionalExtension, Transaction};
+
+use crate::network::Network;
+
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+// Append only, never edit a migration that has shipped
+const MIGRATIONS: &[Migration] = &[
+    create_tables,
+    add_block_header_columns,
+    store_hashes_as_blobs,
+    add_tweak_height,
+    unique_tweaks,
+    create_metadata,
+    add_block_filter,
+    create_outputs,
+    add_tweak_max_value,
+];
+
+/// Schema version this binary writes and understands
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Hashes, txids and tweaks are hex outside the database and stored as BLOBs. Hashes keep the byte
+/// order of their usual hex display (as returned by RPC) so reading one back is just a hex encode
+pub fn to_blob(hex: &str) -> rusqlite::Result<Vec<u8>> {
+    hex::decode(hex).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))
+}
+
+#[derive(Debug)]
+pub enum SchemaError {
+    Sqlite(rusqlite::Error),
+    TooNew { found: u32, supported: u32 },
+    NetworkMismatch { expected: Network, found: String },
+}
+impl std::error::Error for SchemaError {}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SchemaError::Sqlite(err) => write!(f, "{}", err),
+            SchemaError::TooNew { found, supported } => write!(
+                f,
+                "database schema version {} is newer than the {} this binary supports, upgrade the binary",
+                found, supported
+            ),
+            SchemaError::NetworkMismatch { expected, found } => write!(
+                f,
+                "database was indexed from {} but {} was requested",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for SchemaError {
+    fn from(err: rusqlite::Error) -> Self {
+        SchemaError::Sqlite(err)
+    }
+}
+
+pub fn get_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Bring the database up to `SCHEMA_VERSION`, one transaction per migration.
+/// Refuses to touch a database written by a newer binary.
+pub fn migrate(conn: &mut Connection) -> Result<(), SchemaError> {
+    let version = get_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(SchemaError::TooNew { found: version, supported: SCHEMA_VERSION });
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Record `network` in a database that has none yet, or check it matches the one recorded
+pub fn check_network(conn: &Connection, network: Network) -> Result<(), SchemaError> {
+    let recorded: Option<String> = conn
+        .query_row("SELECT value FROM metadata WHERE key = 'network'", [], |row| row.get(0))
+        .optional()?;
+    match recorded {
+        Some(found) if found != network.to_string() => Err(SchemaError::NetworkMismatch { expected: network, found }),
+        Some(_) => Ok(()),
+        None => {
+            conn.execute("INSERT INTO metadata (key, value) VALUES ('network', ?1)", [network.to_string()])?;
+            Ok(())
+        }
+    }
+}
+
+fn has_column(tx: &Transaction, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = tx.prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?;
+    stmt.exists([table, column])
+}
+
+// 1: the original, unversioned, schema
+fn create_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS blocks (
+            height INTEGER PRIMARY KEY,
+            hash TEXT NOT NULL,
+            has_tweaks BOOLEAN NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS tweaks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            block_hash TEXT NOT NULL,
+            tx_id TEXT NOT NULL,
+            tweak TEXT NOT NULL,
+            FOREIGN KEY(block_hash) REFERENCES blocks(hash)
+        );",
+    )
+}
+
+// 2: block header fields, NULL for blocks indexed before this
+fn add_block_header_columns(tx: &Transaction) -> rusqlite::Result<()> {
+    for (column, column_type) in [
+        ("prev_blockhash", "TEXT"),
+        ("timestamp", "INTEGER"),
+        ("merkle_root", "TEXT"),
+        ("tx_count", "INTEGER"),
+    ] {
+        if !has_column(tx, "blocks", column)? {
+            tx.execute(&format!("ALTER TABLE blocks ADD COLUMN {} {}", column, column_type), [])?;
+        }
+    }
+    Ok(())
+}
+
+// `to_blob` for a nullable TEXT column
+fn unhex(value: Option<String>) -> rusqlite::Result<Option<Vec<u8>>> {
+    value.as_deref().map(to_blob).transpose()
+}
+
+// 3: hashes, txids and tweaks as raw bytes rather than hex TEXT, see `to_blob`
+fn store_hashes_as_blobs(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE blocks_new (
+            height INTEGER PRIMARY KEY,
+            hash BLOB NOT NULL,
+            has_tweaks BOOLEAN NOT NULL,
+            prev_blockhash BLOB,
+            timestamp INTEGER,
+            merkle_root BLOB,
+            tx_count INTEGER
+        );
+        CREATE TABLE tweaks_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            block_hash BLOB NOT NULL,
+            tx_id BLOB NOT NULL,
+            tweak BLOB NOT NULL,
+            FOREIGN KEY(block_hash) REFERENCES blocks(hash)
+        );",
+    )?;
+
+    {
+        let mut select = tx.prepare("SELECT height, hash, has_tweaks, prev_blockhash, timestamp, merkle_root, tx_count FROM blocks")?;
+        let mut insert = tx.prepare("INSERT INTO blocks_new VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            insert.execute(rusqlite::params![
+                row.get::<_, u32>(0)?,
+                unhex(row.get(1)?)?,
+                row.get::<_, bool>(2)?,
+                unhex(row.get(3)?)?,
+                row.get::<_, Option<u32>>(4)?,
+                unhex(row.get(5)?)?,
+                row.get::<_, Option<u32>>(6)?,
+            ])?;
+        }
+
+        let mut select = tx.prepare("SELECT id, block_hash, tx_id, tweak FROM tweaks")?;
+        let mut insert = tx.prepare("INSERT INTO tweaks_new VALUES (?1, ?2, ?3, ?4)")?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            insert.execute(rusqlite::params![
+                row.get::<_, i64>(0)?,
+                unhex(row.get(1)?)?,
+                unhex(row.get(2)?)?,
+                unhex(row.get(3)?)?,
+            ])?;
+        }
+    }
+
+    tx.execute_batch(
+        "DROP TABLE tweaks;
+        DROP TABLE blocks;
+        ALTER TABLE blocks_new RENAME TO blocks;
+        ALTER TABLE tweaks_new RENAME TO tweaks;",
+    )
+}
+
+// 4: height on tweaks so they can be looked up and rolled back without joining on the hash,
+// plus indexes for the lookups both binaries make. Tweaks without a stored block keep a NULL height
+fn add_tweak_height(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE tweaks ADD COLUMN height INTEGER;
+        UPDATE tweaks SET height = (SELECT height FROM blocks WHERE blocks.hash = tweaks.block_hash);
+        CREATE INDEX tweaks_height ON tweaks(height);
+        CREATE INDEX tweaks_block_hash ON tweaks(block_hash);
+        CREATE INDEX blocks_hash ON blocks(hash);",
+    )
+}
+
+// 5: one tweak per transaction in a block. Drops the duplicates and orphans left by blocks
+// whose tweaks were written but whose block row never was
+fn unique_tweaks(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "DELETE FROM tweaks WHERE block_hash NOT IN (SELECT hash FROM blocks);
+        DELETE FROM tweaks WHERE id NOT IN (SELECT min(id) FROM tweaks GROUP BY block_hash, tx_id);
+        DROP INDEX tweaks_block_hash;
+        CREATE UNIQUE INDEX tweaks_block_hash_tx_id ON tweaks(block_hash, tx_id);",
+    )
+}
+
+// 6: key/value settings of the database as a whole, like the network it was indexed from
+fn create_metadata(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE metadata (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );",
+    )
+}
+
+// 7: BIP158 style filter over the taproot outputs of a block's tweak eligible transactions,
+// NULL for blocks indexed before filters were built
+fn add_block_filter(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE blocks ADD COLUMN filter BLOB;")
+}
+
+// 8: P2TR outputs of every transaction with a tweak, for clients matching the outputs they derive
+fn create_outputs(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE outputs (
+            height INTEGER NOT NULL,
+            block_hash BLOB NOT NULL,
+            tx_id BLOB NOT NULL,
+            vout INTEGER NOT NULL,
+            pubkey BLOB NOT NULL,
+            value INTEGER NOT NULL,
+            PRIMARY KEY (block_hash, tx_id, vout)
+        );
+        CREATE INDEX outputs_height ON outputs (height);",
+    )
+}
+
+// 9: largest P2TR output value of each tweak's transaction, so tweaks can be filtered by a dust
+// limit. Filled from `outputs` where it has them, NULL for older tweaks
+fn add_tweak_max_value(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE tweaks ADD COLUMN max_value INTEGER;
+        UPDATE tweaks SET max_value = (
+            SELECT max(value) FROM outputs
+            WHERE outputs.block_hash = tweaks.block_hash AND outputs.tx_id = tweaks.tx_id
+        );",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        // unversioned database from before migrations existed
+        conn.execute("CREATE TABLE blocks (height INTEGER PRIMARY KEY, hash TEXT NOT NULL, has_tweaks BOOLEAN NOT NULL)", []).unwrap();
+        conn.execute("CREATE TABLE tweaks (id INTEGER PRIMARY KEY AUTOINCREMENT, block_hash TEXT NOT NULL, tx_id TEXT NOT NULL, tweak TEXT NOT NULL)", []).unwrap();
+        conn.execute("INSERT INTO blocks (height, hash, has_tweaks) VALUES (709632, 'aa', 1)", []).unwrap();
+        conn.execute("INSERT INTO tweaks (block_hash, tx_id, tweak) VALUES ('aa', 'bb', '02cc')", []).unwrap();
+        conn.execute("INSERT INTO tweaks (block_hash, tx_id, tweak) VALUES ('aa', 'bb', '02cc')", []).unwrap();
+        conn.execute("INSERT INTO tweaks (block_hash, tx_id, tweak) VALUES ('dd', 'bb', '02cc')", []).unwrap();
+        assert_eq!(get_version(&conn).unwrap(), 0);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(get_version(&conn).unwrap(), SCHEMA_VERSION);
+        let hash: Vec<u8> = conn.query_row("SELECT hash FROM blocks WHERE prev_blockhash IS NULL", [], |row| row.get(0)).unwrap();
+        assert_eq!(hash, vec![0xaa]);
+        let tweak: Vec<u8> = conn.query_row("SELECT tweak FROM tweaks WHERE block_hash = ?1", [vec![0xaau8]], |row| row.get(0)).unwrap();
+        assert_eq!(tweak, vec![0x02, 0xcc]);
+        // duplicate and orphaned tweaks are gone
+        let count: u32 = conn.query_row("SELECT count(*) FROM tweaks", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+        let height: u32 = conn.query_row("SELECT height FROM tweaks", [], |row| row.get(0)).unwrap();
+        assert_eq!(height, 709632);
+        // no outputs were stored to tell its largest value
+        let max_value: Option<u64> = conn.query_row("SELECT max_value FROM tweaks", [], |row| row.get(0)).unwrap();
+        assert_eq!(max_value, None);
+
+        // running again is a no-op
+        migrate(&mut conn).unwrap();
+
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        assert!(matches!(migrate(&mut conn), Err(SchemaError::TooNew { .. })));
+    }
+
+    #[test]
+    fn test_check_network() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        check_network(&conn, Network::Signet).unwrap();
+        check_network(&conn, Network::Signet).unwrap();
+        assert!(matches!(
+            check_network(&conn, Network::Mainnet),
+            Err(SchemaError::NetworkMismatch { expected: Network::Mainnet, .. })
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+//! Compact binary format for per block tweaks, served by tweak-service to clients that send
+//! `Accept: application/octet-stream`.
+//!
+//! A response is a sequence of blocks in height order, each a 40 byte header followed by its tweaks:
+//!
+//! | bytes    | field                                          |
+//! |----------|------------------------------------------------|
+//! | 4        | height, little endian                          |
+//! | 32       | block hash, in the same byte order as its hex  |
+//! | 4        | number of tweaks `n`, little endian            |
+//! | 33 * `n` | tweaks, compressed public keys                 |
+
+use std::fmt;
+use std::io::{self, Read};
+
+pub const CONTENT_TYPE: &str = "application/octet-stream";
+
+const HEADER_LEN: usize = 40;
+const TWEAK_LEN: usize = 33;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTweaks {
+    pub height: u32,
+    pub block_hash: [u8; 32],
+    pub tweaks: Vec<[u8; TWEAK_LEN]>,
+}
+
+impl BlockTweaks {
+    /// Append the block's header and tweaks to `out`
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.reserve(HEADER_LEN + self.tweaks.len() * TWEAK_LEN);
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.extend_from_slice(&self.block_hash);
+        out.extend_from_slice(&(self.tweaks.len() as u32).to_le_bytes());
+        for tweak in &self.tweaks {
+            out.extend_from_slice(tweak);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(io::Error),
+    // the stream ended inside a block
+    Truncated,
+}
+impl std::error::Error for DecodeError {}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Io(err) => write!(f, "Unable to read tweaks: {}", err),
+            DecodeError::Truncated => write!(f, "Tweak stream ends in the middle of a block"),
+        }
+    }
+}
+
+/// Reads the blocks of a binary response one at a time, so a client can scan a long range
+/// without holding it all in memory. `&[u8]` works as the reader for a response already in memory
+pub struct Decoder<R> {
+    reader: R,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    /// The next block, `None` once the stream ends cleanly between blocks
+    pub fn next_block(&mut self) -> Result<Option<BlockTweaks>, DecodeError> {
+        let mut header = [0u8; HEADER_LEN];
+        if !self.fill(&mut header, true)? {
+            return Ok(None);
+        }
+        let height = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let block_hash = header[4..36].try_into().unwrap();
+        let count = u32::from_le_bytes(header[36..40].try_into().unwrap());
+
+        // the count isn't trusted to size an allocation, a bad one ends as Truncated
+        let mut tweaks = Vec::new();
+        for _ in 0..count {
+            let mut tweak = [0u8; TWEAK_LEN];
+            self.fill(&mut tweak, false)?;
+            tweaks.push(tweak);
+        }
+        Ok(Some(BlockTweaks { height, block_hash, tweaks }))
+    }
+
+    // Fill `buf`, returning false if the stream was already at its end and that is allowed
+    fn fill(&mut self, buf: &mut [u8], allow_end: bool) -> Result<bool, DecodeError> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 && allow_end => return Ok(false),
+                Ok(0) => return Err(DecodeError::Truncated),
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(DecodeError::Io(err)),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Iterator for Decoder<R> {
+    type Item = Result<BlockTweaks, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_block().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let blocks = vec![
+            BlockTweaks { height: 709632, block_hash: [1; 32], tweaks: vec![[2; 33], [3; 33]] },
+            BlockTweaks { height: 709633, block_hash: [4; 32], tweaks: vec![] },
+        ];
+        let mut encoded = Vec::new();
+        for block in &blocks {
+            block.encode(&mut encoded);
+        }
+        assert_eq!(encoded.len(), 2 * HEADER_LEN + 2 * TWEAK_LEN);
+
+        let decoded: Vec<BlockTweaks> = Decoder::new(encoded.as_slice()).collect::<Result<_, _>>().unwrap();
+        assert_eq!(decoded, blocks);
+
+        let mut truncated = Decoder::new(&encoded[..HEADER_LEN + TWEAK_LEN]);
+        assert!(matches!(truncated.next_block(), Err(DecodeError::Truncated)));
+        assert!(Decoder::new(&[][..]).next_block().unwrap().is_none());
+    }
+}
@@ -0,0 +1,600 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use bitcoin::block::Header;
+use bitcoin::consensus::encode::{deserialize, Decodable, VarInt};
+use bitcoin::hashes::{sha256d, Hash, HashEngine};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Block, BlockHash, ScriptBuf, Work};
+use tracing::info;
+
+use crate::chain::PreviousScript;
+use crate::source::{ChainSource, SourceError};
+
+// Scripts longer than this are stored as a bare length in the undo data (see Core's compressor.h)
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+
+#[derive(Debug)]
+pub enum BlockFileError {
+    Io(std::io::Error),
+    Decode(bitcoin::consensus::encode::Error),
+    UndoNotFound(BlockHash),
+    InvalidUndo(String),
+    NoGenesis,
+}
+impl std::error::Error for BlockFileError {}
+
+impl std::fmt::Display for BlockFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockFileError::Io(err) => write!(f, "Block file IO error: {}", err),
+            BlockFileError::Decode(err) => write!(f, "Unable to decode block file data: {}", err),
+            BlockFileError::UndoNotFound(hash) => write!(f, "No undo data found for block {}", hash),
+            BlockFileError::InvalidUndo(err) => write!(f, "Invalid undo data: {}", err),
+            BlockFileError::NoGenesis => write!(f, "Genesis block not found in block files"),
+        }
+    }
+}
+
+impl From<std::io::Error> for BlockFileError {
+    fn from(err: std::io::Error) -> Self {
+        BlockFileError::Io(err)
+    }
+}
+
+impl From<bitcoin::consensus::encode::Error> for BlockFileError {
+    fn from(err: bitcoin::consensus::encode::Error) -> Self {
+        BlockFileError::Decode(err)
+    }
+}
+
+// Reader that undoes the `blocks/xor.dat` obfuscation Core 28+ applies to blk/rev files
+struct XorReader<R> {
+    inner: R,
+    key: [u8; 8],
+    position: u64,
+}
+
+impl<R: Read + Seek> XorReader<R> {
+    fn new(inner: R, key: [u8; 8]) -> Self {
+        Self { inner, key, position: 0 }
+    }
+}
+
+impl<R: Read> Read for XorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        for byte in buf[..read].iter_mut() {
+            *byte ^= self.key[(self.position % 8) as usize];
+            self.position += 1;
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for XorReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct BlockLocation {
+    file: u32,
+    offset: u64,
+    size: u32,
+    prev_blockhash: BlockHash,
+    work: Work,
+}
+
+#[derive(Clone, Copy)]
+struct UndoRecord {
+    offset: u64,
+    size: u32,
+    tx_count: u64,
+}
+
+/// Chain source reading a Bitcoin Core `blocks` directory directly.
+///
+/// Blocks come from `blk*.dat` and previous output scripts from the undo data
+/// in the matching `rev*.dat`, so no RPC connection (or txindex) is required.
+/// The directory should not be written to while indexing, use a stopped node
+/// or a copy of its datadir.
+pub struct BlockFileSource {
+    blocks_dir: PathBuf,
+    xor_key: [u8; 8],
+    active_chain: Vec<BlockHash>,
+    locations: HashMap<BlockHash, BlockLocation>,
+    undo_records: Mutex<HashMap<u32, Vec<UndoRecord>>>,
+}
+
+impl BlockFileSource {
+    /// Scan every block header in `blocks_dir`, taking the most work chain from genesis up to the last
+    /// block with undo data as active
+    pub fn open(blocks_dir: &Path) -> Result<Self, BlockFileError> {
+        let xor_key = match std::fs::read(blocks_dir.join("xor.dat")) {
+            Ok(key) if key.len() == 8 => key.try_into().unwrap(),
+            _ => [0u8; 8],
+        };
+
+        let mut locations = HashMap::new();
+        for file in 0.. {
+            let path = blk_path(blocks_dir, "blk", file);
+            if !path.exists() {
+                break;
+            }
+            let mut reader = XorReader::new(BufReader::new(File::open(&path)?), xor_key);
+            for (offset, size) in scan_records(&mut reader, 0)? {
+                reader.seek(SeekFrom::Start(offset))?;
+                let header = Header::consensus_decode(bitcoin::io::from_std_mut(&mut reader))?;
+                let location = BlockLocation { file, offset, size, prev_blockhash: header.prev_blockhash, work: header.work() };
+                locations.insert(header.block_hash(), location);
+            }
+        }
+
+        let mut source = Self {
+            blocks_dir: blocks_dir.to_path_buf(),
+            xor_key,
+            active_chain: most_work_chain(&locations)?,
+            locations,
+            undo_records: Mutex::new(HashMap::new()),
+        };
+        // a block is written when it arrives but its undo data only once it is connected, the tip
+        // is the last block that got that far
+        while source.active_chain.len() > 1 && !source.has_undo(source.active_chain.last().unwrap())? {
+            source.active_chain.pop();
+        }
+        info!("Found {} blocks in {}, active chain height {}", source.locations.len(), blocks_dir.display(), source.active_chain.len() - 1);
+        Ok(source)
+    }
+
+    fn open_file(&self, prefix: &str, file: u32) -> Result<XorReader<BufReader<File>>, BlockFileError> {
+        let file = File::open(blk_path(&self.blocks_dir, prefix, file))?;
+        Ok(XorReader::new(BufReader::new(file), self.xor_key))
+    }
+
+    fn location(&self, block_hash: &str) -> Result<BlockLocation, SourceError> {
+        block_hash.parse::<BlockHash>().ok()
+            .and_then(|hash| self.locations.get(&hash).copied())
+            .ok_or_else(|| SourceError::NotFound(block_hash.to_string()))
+    }
+
+    fn read_bytes(&self, prefix: &str, file: u32, offset: u64, size: u32) -> Result<Vec<u8>, BlockFileError> {
+        let mut reader = self.open_file(prefix, file)?;
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; size as usize];
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_block(&self, location: &BlockLocation) -> Result<Block, BlockFileError> {
+        Ok(deserialize(&self.read_bytes("blk", location.file, location.offset, location.size)?)?)
+    }
+
+    // Undo records sit in the rev file with the block's file number but not in the same order,
+    // so find the one whose checksum (sha256d of prev block hash || undo data) matches
+    fn read_undo(&self, block: &Block, location: &BlockLocation) -> Result<Vec<Vec<ScriptBuf>>, BlockFileError> {
+        // nothing spent, and genesis has no undo record at all
+        let tx_count = block.txdata.len() as u64 - 1;
+        if tx_count == 0 {
+            return Ok(vec![]);
+        }
+
+        let records = {
+            let mut undo_records = self.undo_records.lock().unwrap();
+            match undo_records.get(&location.file) {
+                Some(records) => records.clone(),
+                None => {
+                    let mut reader = self.open_file("rev", location.file)?;
+                    let mut records = vec![];
+                    for (offset, size) in scan_records(&mut reader, 32)? {
+                        reader.seek(SeekFrom::Start(offset))?;
+                        let tx_count = VarInt::consensus_decode(bitcoin::io::from_std_mut(&mut reader))?.0;
+                        records.push(UndoRecord { offset, size, tx_count });
+                    }
+                    undo_records.insert(location.file, records.clone());
+                    records
+                }
+            }
+        };
+
+        for record in records.iter().filter(|record| record.tx_count == tx_count) {
+            // checksum trails the undo data
+            let data = self.read_bytes("rev", location.file, record.offset, record.size + 32)?;
+            let (undo, checksum) = data.split_at(record.size as usize);
+
+            let mut engine = sha256d::Hash::engine();
+            engine.input(location.prev_blockhash.as_byte_array());
+            engine.input(undo);
+            if sha256d::Hash::from_engine(engine).as_byte_array()[..] == checksum[..] {
+                return parse_block_undo(undo);
+            }
+        }
+
+        Err(BlockFileError::UndoNotFound(block.block_hash()))
+    }
+
+    fn has_undo(&self, hash: &BlockHash) -> Result<bool, BlockFileError> {
+        let location = self.locations[hash];
+        match self.read_undo(&self.read_block(&location)?, &location) {
+            Ok(_) => Ok(true),
+            Err(BlockFileError::UndoNotFound(_)) => Ok(false),
+            Err(BlockFileError::Io(err)) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl ChainSource for BlockFileSource {
+    fn get_block_count(&self) -> Result<u32, SourceError> {
+        Ok(self.active_chain.len() as u32 - 1)
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<String, SourceError> {
+        self.active_chain.get(height as usize)
+            .map(|hash| hash.to_string())
+            .ok_or(SourceError::HeightOutOfRange(height))
+    }
+
+    fn get_block(&self, block_hash: &str) -> Result<String, SourceError> {
+        let location = self.location(block_hash)?;
+        Ok(hex::encode(self.read_bytes("blk", location.file, location.offset, location.size)?))
+    }
+
+    fn get_previous_scripts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, SourceError> {
+        let location = self.location(block_hash)?;
+        let block = self.read_block(&location)?;
+        let undo = self.read_undo(&block, &location)?;
+
+        let mut previous_scripts = vec![];
+        for (tx, spent_scripts) in block.txdata.iter().skip(1).zip(undo) {
+            if tx.input.len() != spent_scripts.len() {
+                return Err(BlockFileError::InvalidUndo(format!("input count mismatch for tx {}", tx.compute_txid())).into());
+            }
+            for (input, script) in tx.input.iter().zip(spent_scripts) {
+                let outpoint = input.previous_output;
+                previous_scripts.push(PreviousScript::new(outpoint.txid.to_string(), outpoint.vout, script.to_hex_string()));
+            }
+        }
+        Ok(previous_scripts)
+    }
+
+    // Undo data already covers every input, there is no transaction index to fall back to
+    fn get_transaction(&self, txid: &str) -> Result<String, SourceError> {
+        Err(SourceError::NotFound(txid.to_string()))
+    }
+}
+
+fn blk_path(blocks_dir: &Path, prefix: &str, file: u32) -> PathBuf {
+    blocks_dir.join(format!("{}{:05}.dat", prefix, file))
+}
+
+// Offset and size of every `magic | size | data | trailer` record, stopping at the zeroed preallocated tail.
+// rev records carry a 32 byte checksum trailer, blk records none
+fn scan_records<R: Read + Seek>(reader: &mut R, trailer: u64) -> Result<Vec<(u64, u32)>, BlockFileError> {
+    let mut records = vec![];
+    let mut position = 0;
+    loop {
+        reader.seek(SeekFrom::Start(position))?;
+        let mut record_header = [0u8; 8];
+        match reader.read_exact(&mut record_header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        if record_header[..4] == [0u8; 4] {
+            break;
+        }
+        let size = u32::from_le_bytes(record_header[4..].try_into().unwrap());
+        records.push((position + 8, size));
+        position += 8 + size as u64 + trailer;
+    }
+    Ok(records)
+}
+
+// Chain of headers from genesis (the only block with an all zero parent) with the most cumulative work
+fn most_work_chain(locations: &HashMap<BlockHash, BlockLocation>) -> Result<Vec<BlockHash>, BlockFileError> {
+    let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+    let mut genesis = None;
+    for (hash, location) in locations {
+        if location.prev_blockhash == BlockHash::all_zeros() {
+            genesis = Some(*hash);
+        } else {
+            children.entry(location.prev_blockhash).or_default().push(*hash);
+        }
+    }
+    let genesis = genesis.ok_or(BlockFileError::NoGenesis)?;
+
+    let genesis_work = locations[&genesis].work;
+    let mut chainwork: HashMap<BlockHash, Work> = HashMap::from([(genesis, genesis_work)]);
+    let mut tip = (genesis, genesis_work);
+    let mut stack = vec![genesis];
+    while let Some(hash) = stack.pop() {
+        let work = chainwork[&hash];
+        if work > tip.1 {
+            tip = (hash, work);
+        }
+        for child in children.get(&hash).into_iter().flatten() {
+            chainwork.insert(*child, work + locations[child].work);
+            stack.push(*child);
+        }
+    }
+
+    let mut chain = vec![tip.0];
+    while let Some(location) = locations.get(chain.last().unwrap()) {
+        if location.prev_blockhash == BlockHash::all_zeros() {
+            break;
+        }
+        chain.push(location.prev_blockhash);
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+// Core's VARINT (serialize.h), an MSB base-128 encoding distinct from CompactSize
+fn read_varint<R: Read>(reader: &mut R) -> Result<u64, BlockFileError> {
+    let mut n: u64 = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        n = (n << 7) | (byte[0] & 0x7f) as u64;
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+        n += 1;
+    }
+}
+
+// CBlockUndo: for every non coinbase tx the spent coins, in input order
+fn parse_block_undo(mut undo: &[u8]) -> Result<Vec<Vec<ScriptBuf>>, BlockFileError> {
+    let tx_count = VarInt::consensus_decode(&mut undo)?.0;
+    let mut block_undo = Vec::with_capacity(tx_count as usize);
+    for _ in 0..tx_count {
+        let coin_count = VarInt::consensus_decode(&mut undo)?.0;
+        let mut tx_undo = Vec::with_capacity(coin_count as usize);
+        for _ in 0..coin_count {
+            let code = read_varint(&mut undo)?;
+            // height > 0 is followed by a legacy, always zero, tx version
+            if code >> 1 > 0 {
+                read_varint(&mut undo)?;
+            }
+            let _compressed_amount = read_varint(&mut undo)?;
+            tx_undo.push(read_compressed_script(&mut undo)?);
+        }
+        block_undo.push(tx_undo);
+    }
+    Ok(block_undo)
+}
+
+// ScriptCompression (compressor.cpp): sizes 0-5 are templates, anything else is size - 6 raw bytes
+fn read_compressed_script<R: Read>(reader: &mut R) -> Result<ScriptBuf, BlockFileError> {
+    let size = read_varint(reader)?;
+    let script = match size {
+        0 | 1 => {
+            let mut hash = [0u8; 20];
+            reader.read_exact(&mut hash)?;
+            let mut script = if size == 0 { vec![0x76, 0xa9, 0x14] } else { vec![0xa9, 0x14] };
+            script.extend_from_slice(&hash);
+            script.extend_from_slice(if size == 0 { &[0x88, 0xac] } else { &[0x87] });
+            script
+        }
+        2..=5 => {
+            let mut key = [0u8; 33];
+            reader.read_exact(&mut key[1..])?;
+            key[0] = size as u8;
+            let mut script = if size < 4 {
+                let mut script = vec![0x21];
+                script.extend_from_slice(&key);
+                script
+            } else {
+                // uncompressed P2PK is stored as the compressed key with parity folded into the size
+                key[0] -= 2;
+                let pubkey = PublicKey::from_slice(&key)
+                    .map_err(|err| BlockFileError::InvalidUndo(err.to_string()))?;
+                let mut script = vec![0x41];
+                script.extend_from_slice(&pubkey.serialize_uncompressed());
+                script
+            };
+            script.push(0xac);
+            script
+        }
+        _ => {
+            let size = size - 6;
+            if size > MAX_SCRIPT_SIZE {
+                // oversized scripts are unspendable, Core skips their bytes and keeps an OP_RETURN in place
+                let skipped = io::copy(&mut reader.take(size), &mut io::sink())?;
+                if skipped < size {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                return Ok(ScriptBuf::new_op_return([]));
+            }
+            let mut script = vec![0u8; size as usize];
+            reader.read_exact(&mut script)?;
+            script
+        }
+    };
+    Ok(ScriptBuf::from_bytes(script))
+}
+
+impl From<BlockFileError> for SourceError {
+    fn from(err: BlockFileError) -> Self {
+        SourceError::BlockFile(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::hashes::hash160;
+    use bitcoin::key::Secp256k1;
+    use bitcoin::secp256k1::SecretKey;
+    use crate::fixture::{build_block, p2tr_script, taproot_spend};
+
+    const MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+    fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+        let mut tmp = vec![];
+        loop {
+            tmp.push((n & 0x7f) as u8 | if tmp.is_empty() { 0 } else { 0x80 });
+            if n <= 0x7f {
+                break;
+            }
+            n = (n >> 7) - 1;
+        }
+        out.extend(tmp.iter().rev());
+    }
+
+    fn write_record(file: &mut Vec<u8>, data: &[u8]) {
+        file.extend_from_slice(&MAGIC);
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(data);
+    }
+
+    fn xor(data: &mut [u8], key: [u8; 8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= key[i % 8];
+        }
+    }
+
+    #[test]
+    fn test_block_file_source() {
+        let secp = Secp256k1::new();
+        let pubkey = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[7u8; 32]).unwrap());
+        let pubkey_hash = hash160::Hash::hash(&pubkey.serialize());
+
+        let genesis = build_block(BlockHash::all_zeros(), 0, vec![]);
+        let (tx_a, _) = taproot_spend(1, vec![p2tr_script(2)]);
+        let (tx_b, _) = taproot_spend(3, vec![p2tr_script(4)]);
+        let block_1 = build_block(genesis.block_hash(), 1, vec![tx_a, tx_b]);
+        let (tx_c, _) = taproot_spend(5, vec![p2tr_script(6)]);
+        let block_2 = build_block(block_1.block_hash(), 2, vec![tx_c]);
+        // received but not connected yet, so without undo data
+        let (tx_d, _) = taproot_spend(7, vec![p2tr_script(8)]);
+        let block_3 = build_block(block_2.block_hash(), 3, vec![tx_d]);
+
+        // spent coins: a raw (P2TR) script, a compressed P2PKH and an uncompressed P2PK
+        let raw_script = p2tr_script(1);
+        let p2pkh_script = ScriptBuf::new_p2pkh(&pubkey_hash.into());
+        let mut p2pk_script = vec![0x41];
+        p2pk_script.extend_from_slice(&pubkey.serialize_uncompressed());
+        p2pk_script.push(0xac);
+
+        let mut undo_1 = vec![2u8];
+        undo_1.push(1);
+        write_varint(&mut undo_1, 2 * 100);
+        write_varint(&mut undo_1, 0);
+        write_varint(&mut undo_1, 9);
+        write_varint(&mut undo_1, raw_script.len() as u64 + 6);
+        undo_1.extend_from_slice(raw_script.as_bytes());
+        undo_1.push(1);
+        write_varint(&mut undo_1, 2 * 100 + 1);
+        write_varint(&mut undo_1, 0);
+        write_varint(&mut undo_1, 9);
+        undo_1.push(0);
+        undo_1.extend_from_slice(pubkey_hash.as_byte_array());
+
+        let mut undo_2 = vec![1u8, 1];
+        write_varint(&mut undo_2, 2 * 100);
+        write_varint(&mut undo_2, 0);
+        write_varint(&mut undo_2, 9);
+        undo_2.push(4 | (pubkey.serialize()[0] & 1));
+        undo_2.extend_from_slice(&pubkey.serialize()[1..]);
+
+        let key = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut blk = vec![];
+        for block in [&genesis, &block_2, &block_1, &block_3] {
+            write_record(&mut blk, &serialize(block));
+        }
+        blk.extend_from_slice(&[0u8; 64]);
+
+        // undo records out of block order, each followed by its checksum
+        let mut rev = vec![];
+        for (block, undo) in [(&block_2, &undo_2), (&block_1, &undo_1)] {
+            write_record(&mut rev, undo);
+            let mut engine = sha256d::Hash::engine();
+            engine.input(block.header.prev_blockhash.as_byte_array());
+            engine.input(undo);
+            rev.extend_from_slice(sha256d::Hash::from_engine(engine).as_byte_array());
+        }
+        xor(&mut blk, key);
+        xor(&mut rev, key);
+
+        let blocks_dir = std::env::temp_dir().join(format!("tweak-indexer-blockfiles-{}", std::process::id()));
+        std::fs::create_dir_all(&blocks_dir).unwrap();
+        File::create(blocks_dir.join("xor.dat")).unwrap().write_all(&key).unwrap();
+        File::create(blocks_dir.join("blk00000.dat")).unwrap().write_all(&blk).unwrap();
+        File::create(blocks_dir.join("rev00000.dat")).unwrap().write_all(&rev).unwrap();
+
+        let source = BlockFileSource::open(&blocks_dir).unwrap();
+        assert_eq!(source.get_block_count().unwrap(), 2);
+        assert_eq!(source.get_block_hash(1).unwrap(), block_1.block_hash().to_string());
+        assert!(matches!(source.get_block_hash(3), Err(SourceError::HeightOutOfRange(3))));
+        assert!(source.get_block(&block_3.block_hash().to_string()).is_ok());
+        assert_eq!(source.get_block(&block_2.block_hash().to_string()).unwrap(), hex::encode(serialize(&block_2)));
+
+        let expected = |block: &Block, scripts: Vec<String>| -> Vec<PreviousScript> {
+            block.txdata.iter().skip(1).flat_map(|tx| &tx.input).zip(scripts)
+                .map(|(input, script)| PreviousScript::new(input.previous_output.txid.to_string(), input.previous_output.vout, script))
+                .collect()
+        };
+        assert_eq!(
+            source.get_previous_scripts(&block_1.block_hash().to_string()).unwrap(),
+            expected(&block_1, vec![raw_script.to_hex_string(), p2pkh_script.to_hex_string()])
+        );
+        assert_eq!(
+            source.get_previous_scripts(&block_2.block_hash().to_string()).unwrap(),
+            expected(&block_2, vec![hex::encode(p2pk_script)])
+        );
+
+        std::fs::remove_dir_all(&blocks_dir).unwrap();
+    }
+
+    #[test]
+    fn test_most_work_chain() {
+        let location = |block: &Block| (block.block_hash(), BlockLocation {
+            file: 0,
+            offset: 0,
+            size: 0,
+            prev_blockhash: block.header.prev_blockhash,
+            work: block.header.work(),
+        });
+        let genesis = build_block(BlockHash::all_zeros(), 0, vec![]);
+        let mut locations = HashMap::from([location(&genesis)]);
+        let mut long = vec![genesis.block_hash()];
+        for height in 1..=3 {
+            let block = build_block(*long.last().unwrap(), height, vec![]);
+            locations.extend([location(&block)]);
+            long.push(block.block_hash());
+        }
+        assert_eq!(most_work_chain(&locations).unwrap(), long);
+
+        // a shorter branch mined at a higher difficulty outweighs it
+        let mut heavy = build_block(genesis.block_hash(), 1, vec![]);
+        heavy.header.bits = bitcoin::CompactTarget::from_consensus(0x1d00ffff);
+        locations.extend([location(&heavy)]);
+        assert_eq!(most_work_chain(&locations).unwrap(), vec![genesis.block_hash(), heavy.block_hash()]);
+
+        locations.remove(&genesis.block_hash());
+        assert!(matches!(most_work_chain(&locations), Err(BlockFileError::NoGenesis)));
+    }
+
+    #[test]
+    fn test_oversized_script_is_skipped() {
+        let raw_script = p2tr_script(1);
+        let mut undo = vec![1u8, 2];
+        for script in [vec![0x6a; MAX_SCRIPT_SIZE as usize + 1], raw_script.to_bytes()] {
+            write_varint(&mut undo, 2 * 100);
+            write_varint(&mut undo, 0);
+            write_varint(&mut undo, 9);
+            write_varint(&mut undo, script.len() as u64 + 6);
+            undo.extend_from_slice(&script);
+        }
+        assert_eq!(parse_block_undo(&undo).unwrap(), vec![vec![ScriptBuf::new_op_return([]), raw_script]]);
+        assert!(parse_block_undo(&undo[..MAX_SCRIPT_SIZE as usize]).is_err());
+    }
+}
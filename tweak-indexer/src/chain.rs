@@ -1,30 +1,39 @@
 use secp256k1::XOnlyPublicKey;
 use bitcoin::consensus::encode::deserialize_hex;
+use bitcoin::bip158::BlockFilterWriter;
 use bitcoin::block::Block;
-use bitcoin::{ScriptBuf, Transaction, WitnessVersion};
+use bitcoin::{OutPoint, ScriptBuf, Transaction, Txid, WitnessVersion};
 use silentpayments::utils::receiving;
 use silentpayments::secp256k1::PublicKey;
-use std::process::{Command, Stdio};
+use std::collections::HashMap;
 use std::error::Error;
-use tracing::{error,warn,debug};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{warn,debug};
 use serde::{Serialize, Deserialize};
-use serde_json;
+use serde_json::json;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct PreviousScript {
     txid: String,
     vout: u32,
     script: String,
 }
 
+impl PreviousScript {
+    pub fn new(txid: String, vout: u32, script: String) -> Self {
+        Self { txid, vout, script }
+    }
+}
+
 use crate::database;
+use crate::rpc::{RpcClient, RpcError};
+use crate::source::{ChainSource, SourceError};
 
 #[derive(Debug)]
 enum ChainError {
     TxOutputNotFound,
     PubKeyFromInput,
-    SegWitVersionGE2,
-    ParseInputTransaction,
 }
 impl std::error::Error for ChainError {}
 
@@ -33,99 +42,166 @@ impl std::fmt::Display for ChainError {
         match self {
             ChainError::TxOutputNotFound => write!(f, "Could not find previous output transaction"),
             ChainError::PubKeyFromInput => write!(f, "Pub Key From Input error"),
-            ChainError::SegWitVersionGE2 => write!(f, "Segwit version 2 or higher not allowed"),
-            ChainError::ParseInputTransaction => write!(f, "Unable to parse previous output transaction")
         }
     }
 }
 
-// take json transaction output and parse with serde to product Vec<PreviousScript>
-pub fn get_block_input_transactions(block_hash: &str) -> Result<Vec<PreviousScript>, Box<dyn Error>> {
-    let transactions_json = match get_block_with_input(&block_hash) {
-        Ok(block_str) => block_str,
-        Err(err) => {
-            error!("Error fetching block: {}", err);
-            return Err(Box::new(ChainError::ParseInputTransaction));
-        }
-    };
-    
-    let previous_scripts: Vec<PreviousScript> = match serde_json::from_str(&transactions_json) {
-        Ok(scripts) => scripts,
-        Err(err) => {
-            error!("Error parsing json transactions: {}", err);
-            return Err(Box::new(ChainError::ParseInputTransaction));
+/// Whether a transaction can pay a silent payment under BIP352, and if not why not
+#[derive(Debug)]
+pub enum Eligibility {
+    Eligible(database::Tweak),
+    Coinbase,
+    // no output is a P2TR script with a valid x-only key
+    NoTaprootOutput,
+    // spending a segwit v2+ output makes the whole transaction ineligible
+    SpendsSegwitV2OrHigher { input: OutPoint },
+    // none of the inputs is a P2TR, P2WPKH, P2SH-P2WPKH or P2PKH spend with an extractable key
+    NoEligibleInputs,
+    // the input keys sum to the point at infinity
+    InputKeysCancelOut,
+}
+
+impl std::fmt::Display for Eligibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Eligibility::Eligible(tweak) => write!(f, "eligible, tweak {}", hex::encode(tweak.tweak)),
+            Eligibility::Coinbase => write!(f, "coinbase transaction"),
+            Eligibility::NoTaprootOutput => write!(f, "no taproot output"),
+            Eligibility::SpendsSegwitV2OrHigher { input } => write!(f, "input {} spends segwit version 2 or higher", input),
+            Eligibility::NoEligibleInputs => write!(f, "no eligible inputs"),
+            Eligibility::InputKeysCancelOut => write!(f, "input public keys sum to zero"),
         }
-    };
-    
+    }
+}
+
+// Verbosity 3 `getblock` only needs to be read as far as each input's previous output script
+#[derive(Deserialize)]
+pub struct VerboseBlock {
+    tx: Vec<VerboseTransaction>,
+}
+
+#[derive(Deserialize)]
+struct VerboseTransaction {
+    vin: Vec<VerboseInput>,
+}
+
+#[derive(Deserialize)]
+struct VerboseInput {
+    txid: Option<String>,
+    vout: Option<u32>,
+    prevout: Option<VerbosePrevout>,
+}
+
+#[derive(Deserialize)]
+struct VerbosePrevout {
+    #[serde(rename = "scriptPubKey")]
+    script_pub_key: VerboseScript,
+}
+
+#[derive(Deserialize)]
+struct VerboseScript {
+    hex: String,
+}
+
+// Collect the previous output script of every non coinbase input in the block
+pub fn get_block_input_transactions(rpc: &RpcClient, block_hash: &str) -> Result<Vec<PreviousScript>, RpcError> {
+    let block = get_block_with_input(rpc, block_hash)?;
+
+    let previous_scripts = block.tx.into_iter()
+        .flat_map(|tx| tx.vin)
+        .filter_map(|input| match (input.txid, input.vout, input.prevout) {
+            (Some(txid), Some(vout), Some(prevout)) => Some(PreviousScript::new(txid, vout, prevout.script_pub_key.hex)),
+            _ => None,
+        })
+        .collect();
+
     Ok(previous_scripts)
 }
 
-pub fn get_block_count() -> Result<String, String> {
-    bcli(&["getblockcount"])
+pub fn get_block_count(rpc: &RpcClient) -> Result<u32, RpcError> {
+    rpc.call("getblockcount", &[])
 }
 
-pub fn get_block_hash(height: u32) -> Result<String, String> {
-    bcli(&["getblockhash", &height.to_string()])
+pub fn get_block_hash(rpc: &RpcClient, height: u32) -> Result<String, RpcError> {
+    rpc.call("getblockhash", &[json!(height)])
 }
 
-pub fn get_block(block_hash: &str) -> Result<String, String> {
-    bcli(&["getblock", block_hash, "0"])
+pub fn get_block(rpc: &RpcClient, block_hash: &str) -> Result<String, RpcError> {
+    rpc.call("getblock", &[json!(block_hash), json!(0)])
 }
 
 // Fetch the long form output to include input previous out (faster than using RPC for each transaction in a block)
-pub fn get_block_with_input(block_hash: &str) -> Result<String, String> {
-    let first_cmd = Command::new("bitcoin-cli")
-        .args(["getblock", block_hash, "3"]) 
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("Failed to execute bitcoin-cli: {}", e))?;
-
-    // Second command: Processing JSON with jq
-    let result = Command::new("jq")
-        .args(&["-c", "[.tx[].vin[] | select(.txid != null) | {txid, vout, script: .prevout.scriptPubKey.hex}]"])
-        .stdin(Stdio::from(first_cmd.stdout.unwrap())) // Pipe stdout from first command
-        .output()
-        .map_err(|e| format!("Failed to execute jq: {}", e))?;
-
-    if !result.status.success() {
-        return Err(format!(
-            "bitcoin-cli error: {}",
-            String::from_utf8_lossy(&result.stderr)
-        ));
-    }
-
-    return Ok(String::from_utf8(result.stdout).unwrap().trim().to_string());
+pub fn get_block_with_input(rpc: &RpcClient, block_hash: &str) -> Result<VerboseBlock, RpcError> {
+    rpc.call("getblock", &[json!(block_hash), json!(3)])
+}
+
+pub fn get_transaction(rpc: &RpcClient, txid: &str) -> Result<String, RpcError> {
+    rpc.call("getrawtransaction", &[json!(txid)])
+}
+
+#[derive(Deserialize)]
+struct BlockTip {
+    height: u32,
 }
 
-pub fn get_transaction(txid: &str) -> Result<String, String> {
-    bcli(&["getrawtransaction", txid])
+// `waitforblockheight` rather than `waitfornewblock` so a block found between the end of an
+// index pass and this call still returns straight away
+pub fn wait_for_block_height(rpc: &RpcClient, height: u32, timeout: Duration) -> Result<u32, RpcError> {
+    let tip: BlockTip = rpc.long_poll("waitforblockheight", &[json!(height), json!(timeout.as_millis() as u64)], timeout)?;
+    Ok(tip.height)
 }
 
-pub fn bcli(args: &[&str]) -> Result<String, String> {
-    let result = Command::new("bitcoin-cli")
-        .args(args)
-        .output()
-        .map_err(|e| format!("Failed to execute bitcoin-cli: {}", e))?;
+// Unknown hashes/txids are reported as `SourceError::NotFound` rather than a raw RPC error
+fn not_found_or(err: RpcError, item: &str) -> SourceError {
+    if err.is_not_found() {
+        SourceError::NotFound(item.to_string())
+    } else {
+        SourceError::Rpc(err)
+    }
+}
+
+impl ChainSource for RpcClient {
+    fn get_block_count(&self) -> Result<u32, SourceError> {
+        Ok(get_block_count(self)?)
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<String, SourceError> {
+        get_block_hash(self, height).map_err(|err| {
+            if err.is_height_out_of_range() {
+                SourceError::HeightOutOfRange(height)
+            } else {
+                SourceError::Rpc(err)
+            }
+        })
+    }
+
+    fn get_block(&self, block_hash: &str) -> Result<String, SourceError> {
+        get_block(self, block_hash).map_err(|err| not_found_or(err, block_hash))
+    }
 
-    if !result.status.success() {
-        return Err(format!(
-            "bitcoin-cli error: {}",
-            String::from_utf8_lossy(&result.stderr)
-        ));
+    fn get_previous_scripts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, SourceError> {
+        get_block_input_transactions(self, block_hash).map_err(|err| not_found_or(err, block_hash))
     }
 
-    return Ok(String::from_utf8(result.stdout).unwrap().trim().to_string());
+    fn get_transaction(&self, txid: &str) -> Result<String, SourceError> {
+        get_transaction(self, txid).map_err(|err| not_found_or(err, txid))
+    }
+
+    fn wait_for_block(&self, height: u32, timeout: Duration) -> Result<Option<u32>, SourceError> {
+        Ok(Some(wait_for_block_height(self, height, timeout)?))
+    }
 }
 
 pub struct Chain<'a> {
-    db: &'a database::Database,
+    source: &'a dyn ChainSource,
     block: Option<Block>,
-    previous_scripts: Option<Vec<PreviousScript>>
+    height: u32,
+    previous_scripts: HashMap<OutPoint, ScriptBuf>,
 }
 
 impl<'a> Chain<'a> {
-    pub fn new(db: &'a database::Database) -> Self {
-        Self { db, block: None, previous_scripts: None }
+    pub fn new(source: &'a dyn ChainSource) -> Self {
+        Self { source, block: None, height: 0, previous_scripts: HashMap::new() }
     }
 
     pub fn set_block(&mut self, block: Block) {
@@ -140,24 +216,44 @@ impl<'a> Chain<'a> {
         self.get_block().block_hash().to_string()
     }
 
-    //Should be set once per block
+    //Should be set once per block, scripts are parsed here so lookups don't have to.
+    //Unparseable entries are left out and fetched from the source like any other missing prevout
     pub fn set_previous_scripts(&mut self, previous_scripts: Vec<PreviousScript>) {
-        self.previous_scripts = Some(previous_scripts);
+        self.previous_scripts = previous_scripts.into_iter().filter_map(|ps| {
+            match (Txid::from_str(&ps.txid), ScriptBuf::from_hex(&ps.script)) {
+                (Ok(txid), Ok(script)) => Some((OutPoint { txid, vout: ps.vout }, script)),
+                _ => {
+                    warn!("Ignoring invalid previous script {}:{}", ps.txid, ps.vout);
+                    None
+                }
+            }
+        }).collect();
     }
 
-    //Return the matching previous output string given txid and vout
-    pub fn find_previous_script(&self, tx_id: &str, vout: u32) -> Option<&PreviousScript> {
-        self.previous_scripts.as_ref()?.iter().find(|ps| ps.txid == tx_id && ps.vout == vout)
+    //Return the previous output script spent by `outpoint`
+    pub fn find_previous_script(&self, outpoint: &OutPoint) -> Option<&ScriptBuf> {
+        self.previous_scripts.get(outpoint)
     }
 
-    fn save_tweak_data(&self, tweak: PublicKey, tx_id: &str) -> Result<(), ChainError> {
-        let block_hash_str = self.block_hash_str().clone();
-        let _ = self.db.insert_tweak(&database::Tweak { 
-            block_hash: block_hash_str, 
-            tweak: tweak.to_string(), 
+    fn tweak_data(&self, tweak: PublicKey, tx_id: &str, outputs: Vec<database::TaprootOutput>) -> database::Tweak {
+        database::Tweak { 
+            height: self.height,
+            block_hash: self.block_hash_str(), 
+            tweak: tweak.serialize(), 
             tx_id: tx_id.to_string(), 
-        });
-        Ok(())
+            outputs,
+        }
+    }
+
+    //Outputs with a valid P2TR scriptpubkey, the only ones that can be a silent payment
+    fn taproot_outputs(&self, transaction: &Transaction) -> Vec<database::TaprootOutput> {
+        transaction.output.iter().enumerate().filter_map(|(vout, output)| {
+            if !output.script_pubkey.is_p2tr() {
+                return None;
+            }
+            let pubkey = XOnlyPublicKey::from_slice(&output.script_pubkey.as_bytes()[2..]).ok()?;
+            Some(database::TaprootOutput { vout: vout as u32, pubkey: pubkey.serialize(), value: output.value.to_sat() })
+        }).collect()
     }
 
     //Determine if this spend script is using segwit version 2 or higher
@@ -173,23 +269,34 @@ impl<'a> Chain<'a> {
     }
 
     // Heavy inspiration from sp-client (https://github.com/cygnet3/sp-client) and rust-silentpayments (https://github.com/cygnet3/rust-silentpayments)
-    fn process_transaction(&self, transaction: &Transaction) -> Result<bool, Box<dyn Error>> {
+    // Errors are failures to look up or parse an input, not ineligible transactions
+    fn process_transaction(&self, transaction: &Transaction) -> Result<Eligibility, Box<dyn Error>> {
+        if transaction.is_coinbase() {
+            return Ok(Eligibility::Coinbase);
+        }
+
+        let taproot_outputs = self.taproot_outputs(transaction);
+        if taproot_outputs.is_empty() {
+            return Ok(Eligibility::NoTaprootOutput);
+        }
 
         //Calculate input pub keys
         let mut input_pubkeys: Vec<PublicKey> = vec![];    
         for input in transaction.input.iter() {
-            if input.previous_output.is_null() {
-                return Ok(false);
-            }
 
             // Fetch the previous transaction
-            let previous_script = if let Some(prev_script) = self.find_previous_script(&input.previous_output.txid.to_string(), input.previous_output.vout) {
-                ScriptBuf::from_hex(&prev_script.script)?
+            let previous_script = if let Some(prev_script) = self.find_previous_script(&input.previous_output) {
+                prev_script.clone()
             } else {
-                warn!("Had to fetch previous input transaction using RPC (txid): {}",transaction.compute_txid());
-                let previous_tx_hex = get_transaction(&input.previous_output.txid.to_string())?;
+                warn!("Had to fetch previous input transaction from chain source (txid): {}",transaction.compute_txid());
+                let previous_tx_hex = self.source.get_transaction(&input.previous_output.txid.to_string())?;
                 let previous_tx: Transaction = deserialize_hex::<Transaction>(&previous_tx_hex)?;
-                assert!(previous_tx.compute_txid() == input.previous_output.txid);
+                if previous_tx.compute_txid() != input.previous_output.txid {
+                    return Err(Box::new(SourceError::WrongTransaction {
+                        txid: input.previous_output.txid.to_string(),
+                        got: previous_tx.compute_txid().to_string(),
+                    }));
+                }
 
                 match previous_tx.output.get(input.previous_output.vout as usize) {
                     Some(output) => output.script_pubkey.clone(),
@@ -197,13 +304,11 @@ impl<'a> Chain<'a> {
                 }
             };
             
-            // Filter transactions by BIP352 consensus on allowed transactions
             if self.is_segwit_gt_v1(&previous_script) {
-                warn!("Segwit > v1: {}",previous_script.to_hex_string());
-                return Err(Box::new(ChainError::SegWitVersionGE2));
+                return Ok(Eligibility::SpendsSegwitV2OrHigher { input: input.previous_output });
             }
 
-            // Collect all input pub keys
+            // Collect the keys of eligible inputs, others don't count towards the tweak
             match receiving::get_pubkey_from_input(
                 &input.script_sig.to_bytes(), 
                 &input.witness.to_vec(), 
@@ -222,8 +327,15 @@ impl<'a> Chain<'a> {
             }
         }
 
+        if input_pubkeys.is_empty() {
+            return Ok(Eligibility::NoEligibleInputs);
+        }
+
         // Get the reference to a vector of public keys for further calculations
         let pubkeys_ref: Vec<&PublicKey> = input_pubkeys.iter().collect();
+        if PublicKey::combine_keys(&pubkeys_ref).is_err() {
+            return Ok(Eligibility::InputKeysCancelOut);
+        }
 
         //Calculate outpoints
         let outpoints: Vec<(String, u32)> = transaction
@@ -238,38 +350,50 @@ impl<'a> Chain<'a> {
         // Calculate the tweak data based on the public keys and outpoints
         let tweak_data = receiving::calculate_tweak_data(&pubkeys_ref, &outpoints)?;
 
-        self.save_tweak_data(tweak_data, &transaction.compute_txid().to_string())?;
-        Ok(true)
+        Ok(Eligibility::Eligible(self.tweak_data(tweak_data, &transaction.compute_txid().to_string(), taproot_outputs)))
     }
 
-    /// Deserializes a block but tracks how much data was consumed
-    pub fn process_transactions(&mut self, block_hex: &String) -> Result<bool, Box<dyn Error>>{
+    /// Deserializes a block and returns the tweaks of its transactions, the caller stores them with the block.
+    /// A transaction that can't be processed is left out, unless it's because the source could not be
+    /// reached, then that `SourceError` is returned so the block is fetched again rather than stored short.
+    /// A source answering with the wrong transaction can't be trusted with the block either, that halts the sync
+    pub fn process_transactions(&mut self, block_hex: &str, height: u32) -> Result<Vec<database::Tweak>, Box<dyn Error>>{
         let block = deserialize_hex::<Block>(block_hex)
             .map_err(|e| format!("Failed to decode block: {}", e))?;
         self.set_block(block.clone());
+        self.height = height;
         
-        let mut has_tweaks: bool = false;
+        let mut tweaks = vec![];
         for tx in block.txdata.iter() {
-            // Filter transactions by BIP352 consensus on allowed transactions
-            // Only process transactions with outputs that have a valid P2TR scriptpubkey
-            let mut has_taproot: bool = false;
-            for output in tx.output.iter() {
-                if output.script_pubkey.is_p2tr() && XOnlyPublicKey::from_slice(&output.script_pubkey.as_bytes()[2..]).is_ok()  {
-                    has_taproot = true;
-                    break;
-                }
-            }
-            if has_taproot {
-                match self.process_transaction(tx) {
-                    Ok(has_tweak) => has_tweaks |= has_tweak,
-                    Err(err) => {
-                        warn!("Error processing tx: {}, block: {}: err: {}", tx.compute_txid(), block.header.block_hash(), err);
+            match self.process_transaction(tx) {
+                Ok(eligibility) => {
+                    debug!("Tx {}: {}", tx.compute_txid(), eligibility);
+                    if let Eligibility::Eligible(tweak) = eligibility {
+                        tweaks.push(tweak);
                     }
                 }
+                Err(err) if err.downcast_ref::<SourceError>()
+                    .is_some_and(|err| err.is_transient() || matches!(err, SourceError::WrongTransaction { .. })) => return Err(err),
+                Err(err) => {
+                    warn!("Error processing tx: {}, block: {}: err: {}", tx.compute_txid(), block.header.block_hash(), err);
+                }
             }
         }
 
-        Ok(has_tweaks)
+        Ok(tweaks)
+    }
+
+    /// GCS filter over the x-only keys of the taproot outputs of `tweaks`, keyed and parameterized
+    /// like a BIP158 basic filter so `bip158::BlockFilter::match_any` tells a client whether any
+    /// output it derives is in the block
+    pub fn taproot_filter(&self, tweaks: &[database::Tweak]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut filter = Vec::new();
+        let mut writer = BlockFilterWriter::new(&mut filter, self.get_block());
+        for output in tweaks.iter().flat_map(|tweak| &tweak.outputs) {
+            writer.add_element(&output.pubkey);
+        }
+        writer.finish()?;
+        Ok(filter)
     }
 }
 
@@ -280,33 +404,282 @@ mod tests {
     use super::*;
     use bitcoin::blockdata::script::Builder;
     use bitcoin::blockdata::opcodes::all::{*};
+    use crate::fixture::{build_block, p2tr_script, spend, MemorySource};
+    use bitcoin::consensus::encode::serialize_hex;
+    use bitcoin::hashes::Hash;
+    use bitcoin::script::Instruction;
+    use bitcoin::{BlockHash, PubkeyHash, ScriptHash, WPubkeyHash, WScriptHash};
 
     #[test]
     fn test_is_segwit_gt_v1() {
-        let db = database::Database::new(":memory:").unwrap();
-        let chain = Chain::new(&db);
+        let source = MemorySource::default();
+        let chain = Chain::new(&source);
 
         // Test empty script
-        assert_eq!(chain.is_segwit_gt_v1(&Builder::new().into_script()), false);
+        assert!(!chain.is_segwit_gt_v1(&Builder::new().into_script()));
 
         // Test with SegWit version 0
         let script_pubkey_v0 = Builder::new().push_opcode(OP_PUSHBYTES_0).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&script_pubkey_v0), false);
+        assert!(!chain.is_segwit_gt_v1(&script_pubkey_v0));
 
         // Test with 0x0101
         let script_pubkey_v1 = Builder::new().push_opcode(OP_PUSHBYTES_1).push_slice([0]).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&script_pubkey_v1), false);
+        assert!(!chain.is_segwit_gt_v1(&script_pubkey_v1));
 
         // Test with Taproot version 1
         let script_pubkey_v1 = Builder::new().push_opcode(OP_PUSHNUM_1).push_slice([1,2,3,4]).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&script_pubkey_v1), false);
+        assert!(!chain.is_segwit_gt_v1(&script_pubkey_v1));
 
         // Test with future version 2
         let script_pubkey_v2 = Builder::new().push_opcode(OP_PUSHNUM_2).push_slice([1,2,3,4,5,6]).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&script_pubkey_v2), true);
+        assert!(chain.is_segwit_gt_v1(&script_pubkey_v2));
 
         // Test with P2SH script
-        let p2sh_script = Builder::new().push_opcode(OP_HASH160).push_slice(&[0x8b, 0xc9, 0xba, 0xf0, 0xcc, 0x16, 0x73, 0xad, 0x8e, 0xdd, 0x14, 0xbe, 0x27, 0xff, 0x2f, 0x07, 0x2f, 0x92, 0xb1, 0x05]).push_opcode(OP_EQUAL).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&p2sh_script), false);
+        let p2sh_script = Builder::new().push_opcode(OP_HASH160).push_slice([0x8b, 0xc9, 0xba, 0xf0, 0xcc, 0x16, 0x73, 0xad, 0x8e, 0xdd, 0x14, 0xbe, 0x27, 0xff, 0x2f, 0x07, 0x2f, 0x92, 0xb1, 0x05]).push_opcode(OP_EQUAL).into_script();
+        assert!(!chain.is_segwit_gt_v1(&p2sh_script));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_eligibility() {
+        use bitcoin::key::{Parity, Secp256k1};
+        use bitcoin::Witness;
+
+        let source = MemorySource::default();
+        let mut chain = Chain::new(&source);
+        chain.set_block(build_block(BlockHash::all_zeros(), 1, vec![]));
+        let outpoint = |seed: u8| OutPoint { txid: Txid::from_byte_array([seed; 32]), vout: 0 };
+
+        // a P2TR output key and a P2WPKH key that is its negation
+        let secp = Secp256k1::new();
+        let (xonly, _) = bitcoin::secp256k1::Keypair::from_seckey_slice(&secp, &[5; 32]).unwrap().x_only_public_key();
+        let negated = bitcoin::PublicKey::new(xonly.public_key(Parity::Odd));
+        let p2tr = ScriptBuf::new_p2tr_tweaked(bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(xonly));
+        let p2wpkh = ScriptBuf::new_p2wpkh(&negated.wpubkey_hash().unwrap());
+
+        chain.set_previous_scripts(vec![
+            PreviousScript::new(outpoint(1).txid.to_string(), 0, p2tr.to_hex_string()),
+            PreviousScript::new(outpoint(2).txid.to_string(), 0, ScriptBuf::new_p2wsh(&WScriptHash::all_zeros()).to_hex_string()),
+            PreviousScript::new(outpoint(3).txid.to_string(), 0, Builder::new().push_opcode(OP_PUSHNUM_2).push_slice([7; 32]).into_script().to_hex_string()),
+            PreviousScript::new(outpoint(4).txid.to_string(), 0, p2wpkh.to_hex_string()),
+        ]);
+        let eligibility = |inputs: &[u8], outputs: Vec<ScriptBuf>| {
+            let mut tx = spend(outpoint(inputs[0]), outputs);
+            for seed in &inputs[1..] {
+                tx.input.push(bitcoin::TxIn { previous_output: outpoint(*seed), ..tx.input[0].clone() });
+            }
+            // P2WPKH spends carry the key as the last witness item
+            for input in tx.input.iter_mut().filter(|input| input.previous_output == outpoint(4)) {
+                input.witness = Witness::from_slice(&[vec![1u8; 71], negated.to_bytes()]);
+            }
+            chain.process_transaction(&tx).unwrap()
+        };
+
+        assert!(matches!(chain.process_transaction(&chain.get_block().txdata[0]).unwrap(), Eligibility::Coinbase));
+        assert!(matches!(eligibility(&[1], vec![ScriptBuf::new_op_return([0u8; 4])]), Eligibility::NoTaprootOutput));
+        assert!(matches!(eligibility(&[1], vec![p2tr_script(9)]), Eligibility::Eligible(_)));
+        // ineligible inputs are ignored as long as one input is eligible
+        assert!(matches!(eligibility(&[2, 1], vec![p2tr_script(9)]), Eligibility::Eligible(_)));
+        assert!(matches!(eligibility(&[2], vec![p2tr_script(9)]), Eligibility::NoEligibleInputs));
+        assert!(matches!(eligibility(&[1, 3], vec![p2tr_script(9)]), Eligibility::SpendsSegwitV2OrHigher { input } if input == outpoint(3)));
+        assert!(matches!(eligibility(&[1, 4], vec![p2tr_script(9)]), Eligibility::InputKeysCancelOut));
+    }
+
+    // Receiving cases of the BIP352 send_and_receive_test_vectors.json
+    #[test]
+    fn test_bip352_vectors() {
+        let vectors: serde_json::Value = serde_json::from_str(include_str!("../fixtures/bip352/send_and_receive_test_vectors.json")).unwrap();
+        let source = MemorySource::default();
+
+        let mut cases = 0;
+        for vector in vectors.as_array().unwrap() {
+            for case in vector["receiving"].as_array().unwrap() {
+                let given = &case["given"];
+                let mut previous_scripts = vec![];
+                let input = given["vin"].as_array().unwrap().iter().map(|vin| {
+                    let txid = vin["txid"].as_str().unwrap();
+                    let vout = vin["vout"].as_u64().unwrap() as u32;
+                    previous_scripts.push(PreviousScript::new(txid.to_string(), vout, vin["prevout"]["scriptPubKey"]["hex"].as_str().unwrap().to_string()));
+                    let witness = match vin["txinwitness"].as_str().unwrap() {
+                        "" => bitcoin::Witness::new(),
+                        witness => bitcoin::consensus::encode::deserialize_hex(witness).unwrap(),
+                    };
+                    bitcoin::TxIn {
+                        previous_output: OutPoint { txid: Txid::from_str(txid).unwrap(), vout },
+                        script_sig: ScriptBuf::from_hex(vin["scriptSig"].as_str().unwrap()).unwrap(),
+                        sequence: bitcoin::Sequence::MAX,
+                        witness,
+                    }
+                }).collect();
+                let output = given["outputs"].as_array().unwrap().iter().map(|key| {
+                    let mut script = vec![0x51, 0x20];
+                    script.extend(hex::decode(key.as_str().unwrap()).unwrap());
+                    bitcoin::TxOut { value: bitcoin::Amount::from_sat(1_000), script_pubkey: ScriptBuf::from_bytes(script) }
+                }).collect();
+                let transaction = Transaction {
+                    version: bitcoin::transaction::Version::TWO,
+                    lock_time: bitcoin::absolute::LockTime::ZERO,
+                    input,
+                    output,
+                };
+
+                let mut chain = Chain::new(&source);
+                chain.set_block(build_block(BlockHash::all_zeros(), 1, vec![transaction.clone()]));
+                chain.set_previous_scripts(previous_scripts);
+                let eligibility = chain.process_transaction(&transaction).unwrap();
+                match (case["expected"]["tweak"].as_str(), eligibility) {
+                    (Some(expected), Eligibility::Eligible(tweak)) => assert_eq!(hex::encode(tweak.tweak), expected, "{}", vector["comment"]),
+                    (None, Eligibility::Eligible(tweak)) => panic!("{}: unexpected tweak {}", vector["comment"], hex::encode(tweak.tweak)),
+                    (Some(_), eligibility) => panic!("{}: no tweak, {}", vector["comment"], eligibility),
+                    (None, _) => {}
+                }
+                cases += 1;
+            }
+        }
+        assert_eq!(cases, 29);
+    }
+
+    // Block of `inputs` single input taproot spends, with the previous scripts of all of them
+    fn large_block(inputs: u32) -> (String, Vec<PreviousScript>) {
+        let mut transactions = vec![];
+        let mut previous_scripts = vec![];
+        for i in 0..inputs {
+            let previous_output = OutPoint { txid: Txid::hash(&i.to_le_bytes()), vout: i % 4 };
+            let seed = (i % 250) as u8 + 1;
+            transactions.push(spend(previous_output, vec![p2tr_script(seed)]));
+            previous_scripts.push(PreviousScript::new(previous_output.txid.to_string(), previous_output.vout, p2tr_script(seed).to_hex_string()));
+        }
+        // the node doesn't return them in spend order
+        previous_scripts.reverse();
+        (serialize_hex(&build_block(BlockHash::all_zeros(), 840000, transactions)), previous_scripts)
+    }
+
+    #[test]
+    fn test_find_previous_script() {
+        let (_, previous_scripts) = large_block(10);
+        let source = MemorySource::default();
+        let mut chain = Chain::new(&source);
+        chain.set_previous_scripts(previous_scripts.clone());
+
+        for ps in previous_scripts {
+            let outpoint = OutPoint { txid: Txid::from_str(&ps.txid).unwrap(), vout: ps.vout };
+            assert_eq!(chain.find_previous_script(&outpoint).unwrap().to_hex_string(), ps.script);
+        }
+        assert!(chain.find_previous_script(&OutPoint::null()).is_none());
+    }
+
+    // Has every block but can't be reached for transactions
+    struct Overloaded(MemorySource);
+
+    impl ChainSource for Overloaded {
+        fn get_block_count(&self) -> Result<u32, SourceError> { self.0.get_block_count() }
+        fn get_block_hash(&self, height: u32) -> Result<String, SourceError> { self.0.get_block_hash(height) }
+        fn get_block(&self, block_hash: &str) -> Result<String, SourceError> { self.0.get_block(block_hash) }
+        fn get_previous_scripts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, SourceError> { self.0.get_previous_scripts(block_hash) }
+        fn get_transaction(&self, _txid: &str) -> Result<String, SourceError> { Err(SourceError::Rpc(RpcError::Http(503))) }
+    }
+
+    // Answers every transaction lookup with the same unrelated transaction
+    struct Mistaken(MemorySource);
+
+    impl ChainSource for Mistaken {
+        fn get_block_count(&self) -> Result<u32, SourceError> { self.0.get_block_count() }
+        fn get_block_hash(&self, height: u32) -> Result<String, SourceError> { self.0.get_block_hash(height) }
+        fn get_block(&self, block_hash: &str) -> Result<String, SourceError> { self.0.get_block(block_hash) }
+        fn get_previous_scripts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, SourceError> { self.0.get_previous_scripts(block_hash) }
+        fn get_transaction(&self, _txid: &str) -> Result<String, SourceError> { Ok(serialize_hex(&spend(OutPoint::null(), vec![p2tr_script(1)]))) }
+    }
+
+    #[test]
+    fn test_unreachable_previous_transaction() {
+        let (block_hex, _) = large_block(3);
+
+        // a transaction the source doesn't have is left out of the block
+        let source = MemorySource::default();
+        assert!(Chain::new(&source).process_transactions(&block_hex, 840000).unwrap().is_empty());
+
+        // but one it can't be asked for fails the whole block so it is fetched again
+        let source = Overloaded(MemorySource::default());
+        let err = Chain::new(&source).process_transactions(&block_hex, 840000).unwrap_err();
+        assert!(err.downcast_ref::<SourceError>().unwrap().is_transient());
+
+        // and one it answers with the wrong transaction fails it too, rather than panicking
+        let source = Mistaken(MemorySource::default());
+        let err = Chain::new(&source).process_transactions(&block_hex, 840000).unwrap_err();
+        assert!(matches!(err.downcast_ref::<SourceError>(), Some(SourceError::WrongTransaction { .. })));
+    }
+
+    #[test]
+    fn test_taproot_filter() {
+        let source = MemorySource::synthetic();
+        let block_hash = source.get_block_hash(709635).unwrap();
+        let mut chain = Chain::new(&source);
+        chain.set_previous_scripts(source.get_previous_scripts(&block_hash).unwrap());
+        let tweaks = chain.process_transactions(&source.get_block(&block_hash).unwrap(), 709635).unwrap();
+        let filter = bitcoin::bip158::BlockFilter::new(&chain.taproot_filter(&tweaks).unwrap());
+
+        let block = chain.get_block();
+        for tx in &block.txdata {
+            let tweak = tweaks.iter().find(|tweak| tweak.tx_id == tx.compute_txid().to_string());
+            for (vout, output) in tx.output.iter().enumerate().filter(|(_, output)| output.script_pubkey.is_p2tr()) {
+                let key = &output.script_pubkey.as_bytes()[2..];
+                assert_eq!(filter.match_any(&block.block_hash(), std::iter::once(key)).unwrap(), tweak.is_some());
+                if let Some(tweak) = tweak {
+                    let stored = tweak.outputs.iter().find(|stored| stored.vout == vout as u32).unwrap();
+                    assert_eq!((&stored.pubkey[..], stored.value), (key, output.value.to_sat()));
+                }
+            }
+        }
+        assert!(!filter.match_any(&block.block_hash(), std::iter::once(&[7u8; 32][..])).unwrap());
+    }
+
+    // Mainnet block 702861 from bitcoin-test-data, 2500 transactions spending 6517 inputs. Its `getblock 3`
+    // prevouts weren't recorded with it, so they're rebuilt from the spends, which commit to the previous
+    // script of every P2PKH, P2SH and segwit v0 input. Others (P2PK, bare multisig) are left to the source
+    fn mainnet_block() -> (Block, Vec<PreviousScript>) {
+        let block: Block = bitcoin::consensus::deserialize(bitcoin_test_data::blocks::mainnet_702861()).unwrap();
+        let previous_scripts = block.txdata.iter().skip(1).flat_map(|tx| &tx.input).filter_map(|input| {
+            let pushes: Vec<&[u8]> = input.script_sig.instructions()
+                .filter_map(|instruction| match instruction {
+                    Ok(Instruction::PushBytes(bytes)) => Some(bytes.as_bytes()),
+                    _ => None,
+                })
+                .collect();
+            let script = match (pushes.as_slice(), input.witness.len()) {
+                ([_, pubkey], 0) if pubkey.len() == 33 || pubkey.len() == 65 => ScriptBuf::new_p2pkh(&PubkeyHash::hash(pubkey)),
+                ([.., redeem_script], 0) if pushes.len() > 1 => ScriptBuf::new_p2sh(&ScriptHash::hash(redeem_script)),
+                ([redeem_script], 2..) => ScriptBuf::new_p2sh(&ScriptHash::hash(redeem_script)),
+                ([], 2) if input.witness[1].len() == 33 => ScriptBuf::new_p2wpkh(&WPubkeyHash::hash(&input.witness[1])),
+                ([], 2..) => ScriptBuf::new_p2wsh(&WScriptHash::hash(input.witness.last().unwrap())),
+                _ => return None,
+            };
+            Some(PreviousScript::new(input.previous_output.txid.to_string(), input.previous_output.vout, script.to_hex_string()))
+        }).collect();
+        (block, previous_scripts)
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_process_mainnet_block
+    #[test]
+    #[ignore]
+    fn bench_process_mainnet_block() {
+        let (mut block, previous_scripts) = mainnet_block();
+        // rebuilt from the spending inputs, a prevout left out would be fetched from the empty source
+        // and skip its transaction
+        assert_eq!(previous_scripts.len(), block.txdata.iter().skip(1).map(|tx| tx.input.len()).sum::<usize>());
+        assert_eq!(previous_scripts.len(), 6517);
+
+        // mined before taproot activated, so as is no transaction gets past its outputs. With each paying
+        // a taproot output every input goes through key extraction and a tweak is computed
+        for tx in block.txdata.iter_mut().skip(1) {
+            tx.output[0].script_pubkey = p2tr_script(1);
+        }
+
+        let source = MemorySource::default();
+        let block_hex = serialize_hex(&block);
+        let mut chain = Chain::new(&source);
+        let start = std::time::Instant::now();
+        chain.set_previous_scripts(previous_scripts);
+        let tweaks = chain.process_transactions(&block_hex, 702861).unwrap();
+        println!("{} tweaks in {:?}", tweaks.len(), start.elapsed());
+        assert_eq!(tweaks.len(), 2255);
+    }
+}
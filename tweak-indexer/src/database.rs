@@ -1,18 +1,67 @@
 
+use std::collections::HashMap;
 use rusqlite::{params, Connection, Result};
+use tweak_common::network::Network;
+use tweak_common::schema::{self, to_blob, SchemaError};
 
 #[derive(Debug)]
 pub struct Block {
     pub height: u32,
     pub hash: String,
     pub has_tweaks: bool,
+    // Header fields, `None` for rows indexed before they were stored
+    pub prev_blockhash: Option<String>,
+    pub timestamp: Option<u32>,
+    pub merkle_root: Option<String>,
+    pub tx_count: Option<u32>,
+    // `Chain::taproot_filter`, `None` for rows indexed before filters were built
+    pub filter: Option<Vec<u8>>,
+}
+
+const BLOCK_COLUMNS: &str = "height, hash, has_tweaks, prev_blockhash, timestamp, merkle_root, tx_count, filter";
+
+fn from_blob(blob: Option<Vec<u8>>) -> Option<String> {
+    blob.map(hex::encode)
+}
+
+fn block_from_row(row: &rusqlite::Row) -> Result<Block> {
+    Ok(Block {
+        height: row.get(0)?,
+        hash: hex::encode(row.get::<_, Vec<u8>>(1)?),
+        has_tweaks: row.get(2)?,
+        prev_blockhash: from_blob(row.get(3)?),
+        timestamp: row.get(4)?,
+        merkle_root: from_blob(row.get(5)?),
+        tx_count: row.get(6)?,
+        filter: row.get(7)?,
+    })
 }
 
 #[derive(Debug)]
 pub struct Tweak {
+    pub height: u32,
     pub block_hash: String,
     pub tx_id: String,
-    pub tweak: String,
+    // Compressed public key
+    pub tweak: [u8; 33],
+    pub outputs: Vec<TaprootOutput>,
+}
+
+impl Tweak {
+    // Largest taproot output, stored with the tweak for dust limit filtering
+    pub fn max_value(&self) -> Option<u64> {
+        self.outputs.iter().map(|output| output.value).max()
+    }
+}
+
+// A P2TR output of a transaction with a tweak
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaprootOutput {
+    pub vout: u32,
+    // x-only output key
+    pub pubkey: [u8; 32],
+    // sats
+    pub value: u64,
 }
 
 pub struct Database {
@@ -20,58 +69,98 @@ pub struct Database {
 }
 
 impl Database {
-    pub fn new(db_path: &str) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS blocks (
-                height INTEGER PRIMARY KEY,
-                hash TEXT NOT NULL,
-                has_tweaks BOOLEAN NOT NULL
-            )",
-            [],
-        )?;
-        
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS tweaks (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                block_hash TEXT NOT NULL,
-                tx_id TEXT NOT NULL,
-                tweak TEXT NOT NULL,
-                FOREIGN KEY(block_hash) REFERENCES blocks(hash)
-            )",
-            [],
-        )?;
-
+    pub fn new(db_path: &str) -> std::result::Result<Self, SchemaError> {
+        let mut conn = Connection::open(db_path)?;
+        schema::migrate(&mut conn)?;
         Ok(Self { conn })
     }
 
-    pub fn insert_block(&self, block: &Block) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO blocks (height, hash, has_tweaks) VALUES (?1, ?2, ?3)",
-            params![block.height, block.hash, block.has_tweaks],
-        )?;
-        Ok(())
+    // Refuse a database indexed from another network, recording the network in a new one
+    pub fn check_network(&self, network: Network) -> std::result::Result<(), SchemaError> {
+        schema::check_network(&self.conn, network)
     }
 
-    pub fn insert_tweak(&self, tweak: &Tweak) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO tweaks (block_hash, tx_id, tweak) VALUES (?1, ?2, ?3)",
-            params![tweak.block_hash, tweak.tx_id, tweak.tweak],
+    // Store a block and its tweaks in one transaction, so a crash never leaves a partial block.
+    // Re-inserting a block replaces what was stored for it
+    pub fn insert_block(&self, block: &Block, tweaks: &[Tweak]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            &format!("INSERT OR REPLACE INTO blocks ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)", BLOCK_COLUMNS),
+            params![
+                block.height,
+                to_blob(&block.hash)?,
+                block.has_tweaks,
+                block.prev_blockhash.as_deref().map(to_blob).transpose()?,
+                block.timestamp,
+                block.merkle_root.as_deref().map(to_blob).transpose()?,
+                block.tx_count,
+                block.filter,
+            ],
         )?;
-        Ok(())
+
+        let mut stmt = tx.prepare("INSERT OR REPLACE INTO tweaks (height, block_hash, tx_id, tweak, max_value) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+        for tweak in tweaks {
+            let max_value = tweak.max_value().map(|value| value as i64);
+            stmt.execute(params![tweak.height, to_blob(&tweak.block_hash)?, to_blob(&tweak.tx_id)?, tweak.tweak, max_value])?;
+        }
+        drop(stmt);
+
+        let mut stmt = tx.prepare("INSERT OR REPLACE INTO outputs (height, block_hash, tx_id, vout, pubkey, value) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+        for tweak in tweaks {
+            for output in &tweak.outputs {
+                stmt.execute(params![tweak.height, to_blob(&tweak.block_hash)?, to_blob(&tweak.tx_id)?, output.vout, output.pubkey, output.value as i64])?;
+            }
+        }
+        drop(stmt);
+        tx.commit()
+    }
+
+    pub fn get_block_by_height(&self, height: u32) -> Result<Option<Block>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM blocks WHERE height = ?1", BLOCK_COLUMNS))?;
+        let mut blocks_iter = stmt.query_map(params![height], block_from_row)?;
+
+        blocks_iter.next().transpose()
     }
 
-    pub fn get_block(&self, block_hash: &str) -> Result<Vec<Block>> {
-        let mut stmt = self.conn.prepare("SELECT height, hash, has_tweaks FROM blocks WHERE hash = ?1")?;
-        let blocks_iter = stmt.query_map(params![block_hash], |row| {
-            Ok(Block {
+    // Drop every block at or above `height` along with its tweaks, used to unwind a reorg
+    pub fn remove_blocks_from(&self, height: u32) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM tweaks WHERE height >= ?1", params![height])?;
+        tx.execute("DELETE FROM outputs WHERE height >= ?1", params![height])?;
+        tx.execute("DELETE FROM blocks WHERE height >= ?1", params![height])?;
+        tx.commit()
+    }
+
+    pub fn get_tweaks_by_height(&self, height: u32) -> Result<Vec<Tweak>> {
+        let mut outputs = self.get_outputs_by_height(height)?;
+        let mut stmt = self.conn.prepare("SELECT height, block_hash, tx_id, tweak FROM tweaks WHERE height = ?1")?;
+        let tweaks_iter = stmt.query_map(params![height], |row| {
+            let tx_id = hex::encode(row.get::<_, Vec<u8>>(2)?);
+            Ok(Tweak {
                 height: row.get(0)?,
-                hash: row.get(1)?,
-                has_tweaks: row.get(2)?,
+                block_hash: hex::encode(row.get::<_, Vec<u8>>(1)?),
+                outputs: outputs.remove(&tx_id).unwrap_or_default(),
+                tx_id,
+                tweak: row.get(3)?,
             })
         })?;
 
-        Ok(blocks_iter.filter_map(Result::ok).collect())
+        tweaks_iter.collect()
+    }
+
+    // Taproot outputs stored at `height` by txid, in output order
+    fn get_outputs_by_height(&self, height: u32) -> Result<HashMap<String, Vec<TaprootOutput>>> {
+        let mut stmt = self.conn.prepare("SELECT tx_id, vout, pubkey, value FROM outputs WHERE height = ?1 ORDER BY tx_id, vout")?;
+        let mut rows = stmt.query(params![height])?;
+        let mut outputs: HashMap<String, Vec<TaprootOutput>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            outputs.entry(hex::encode(row.get::<_, Vec<u8>>(0)?)).or_default().push(TaprootOutput {
+                vout: row.get(1)?,
+                pubkey: row.get(2)?,
+                value: row.get::<_, i64>(3)? as u64,
+            });
+        }
+        Ok(outputs)
     }
 
     pub fn get_highest_block(&self) -> Result<u32> {
@@ -114,3 +203,60 @@ impl Database {
         let _ = self.conn.close();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_round_trip() {
+        let db = Database::new(":memory:").unwrap();
+        let block = Block {
+            height: 709633,
+            hash: String::from("bb"),
+            has_tweaks: false,
+            prev_blockhash: Some(String::from("aa")),
+            timestamp: Some(1636866927),
+            merkle_root: Some(String::from("cc")),
+            tx_count: Some(3),
+            filter: Some(vec![0]),
+        };
+        db.insert_block(&block, &[]).unwrap();
+        let stored = db.get_block_by_height(709633).unwrap().unwrap();
+        assert_eq!((stored.hash.as_str(), stored.has_tweaks), ("bb", false));
+        assert_eq!((stored.prev_blockhash.as_deref(), stored.merkle_root.as_deref()), (Some("aa"), Some("cc")));
+        assert_eq!((stored.timestamp, stored.tx_count), (Some(1636866927), Some(3)));
+        assert_eq!(stored.filter, Some(vec![0]));
+        assert!(db.get_block_by_height(709634).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_insert_block_is_idempotent() {
+        let db = Database::new(":memory:").unwrap();
+        let block = Block {
+            height: 840000,
+            hash: String::from("aa"),
+            has_tweaks: true,
+            prev_blockhash: None,
+            timestamp: None,
+            merkle_root: None,
+            tx_count: Some(2),
+            filter: None,
+        };
+        let output = TaprootOutput { vout: 1, pubkey: [3; 32], value: 10_000 };
+        let tweaks = [Tweak { height: 840000, block_hash: String::from("aa"), tx_id: String::from("bb"), tweak: [2; 33], outputs: vec![output.clone()] }];
+
+        db.insert_block(&block, &tweaks).unwrap();
+        db.insert_block(&block, &tweaks).unwrap();
+        let stored = db.get_tweaks_by_height(840000).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].outputs, vec![output]);
+        let max_value: Option<u64> = db.conn.query_row("SELECT max_value FROM tweaks", [], |row| row.get(0)).unwrap();
+        assert_eq!(max_value, Some(10_000));
+
+        // a bad tweak rolls back the whole block
+        let bad = [Tweak { height: 840001, block_hash: String::from("cc"), tx_id: String::from("not hex"), tweak: [2; 33], outputs: vec![] }];
+        assert!(db.insert_block(&Block { height: 840001, hash: String::from("cc"), ..block }, &bad).is_err());
+        assert!(db.get_block_by_height(840001).unwrap().is_none());
+    }
+}
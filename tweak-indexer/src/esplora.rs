@@ -0,0 +1,259 @@
+use std::io::Read;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::chain::PreviousScript;
+use crate::source::{with_retries, ChainSource, SourceError};
+
+// Esplora pages `/block/:hash/txs/:start_index` in fixed chunks
+const TXS_PAGE_SIZE: usize = 25;
+
+#[derive(Debug)]
+pub enum EsploraError {
+    Transport(String),
+    Http(u16, String),
+    Parse(String),
+}
+impl std::error::Error for EsploraError {}
+
+impl EsploraError {
+    /// The server could not be reached, is rate limiting us or failing for now
+    pub fn is_transient(&self) -> bool {
+        matches!(self, EsploraError::Transport(_) | EsploraError::Http(429 | 500..=599, _))
+    }
+}
+
+impl std::fmt::Display for EsploraError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EsploraError::Transport(err) => write!(f, "Esplora transport error: {}", err),
+            EsploraError::Http(status, body) => write!(f, "Esplora HTTP status {}: {}", status, body),
+            EsploraError::Parse(err) => write!(f, "Unable to parse Esplora response: {}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EsploraConfig {
+    pub url: String,
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+#[derive(Deserialize)]
+struct EsploraBlock {
+    tx_count: usize,
+}
+
+#[derive(Deserialize)]
+struct EsploraTransaction {
+    vin: Vec<EsploraInput>,
+}
+
+#[derive(Deserialize)]
+struct EsploraInput {
+    txid: String,
+    vout: u32,
+    is_coinbase: bool,
+    prevout: Option<EsploraPrevout>,
+}
+
+#[derive(Deserialize)]
+struct EsploraPrevout {
+    scriptpubkey: String,
+}
+
+/// Chain source for an electrs/esplora REST API, whose block transactions already carry prevouts
+pub struct EsploraSource {
+    agent: ureq::Agent,
+    url: String,
+    retries: u32,
+    stop: Option<&'static AtomicBool>,
+}
+
+impl EsploraSource {
+    pub fn new(config: EsploraConfig) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(config.timeout)
+            .build();
+        Self { agent, url: config.url.trim_end_matches('/').to_string(), retries: config.retries, stop: None }
+    }
+
+    /// Give up retrying a request once `stop` is set
+    pub fn stop_on(self, stop: &'static AtomicBool) -> Self {
+        Self { stop: Some(stop), ..self }
+    }
+
+    fn send(&self, path: &str) -> Result<ureq::Response, EsploraError> {
+        match self.agent.get(&format!("{}{}", self.url, path)).call() {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => {
+                Err(EsploraError::Http(status, response.into_string().unwrap_or_default()))
+            }
+            Err(err) => Err(EsploraError::Transport(err.to_string())),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<ureq::Response, EsploraError> {
+        with_retries(&format!("Esplora GET {}", path), self.retries, self.stop, EsploraError::is_transient, || self.send(path))
+    }
+
+    fn get_text(&self, path: &str) -> Result<String, EsploraError> {
+        self.get(path)?
+            .into_string()
+            .map(|text| text.trim().to_string())
+            .map_err(|e| EsploraError::Transport(e.to_string()))
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, EsploraError> {
+        self.get(path)?
+            .into_json()
+            .map_err(|e| EsploraError::Parse(e.to_string()))
+    }
+
+    fn get_bytes(&self, path: &str) -> Result<Vec<u8>, EsploraError> {
+        let mut bytes = vec![];
+        self.get(path)?
+            .into_reader()
+            .read_to_end(&mut bytes)
+            .map_err(|e| EsploraError::Transport(e.to_string()))?;
+        Ok(bytes)
+    }
+}
+
+// Esplora answers unknown heights, hashes and txids with a 404, reported as `not_found`
+fn map_404(err: EsploraError, not_found: SourceError) -> SourceError {
+    match err {
+        EsploraError::Http(404, _) => not_found,
+        err => SourceError::Esplora(err),
+    }
+}
+
+impl ChainSource for EsploraSource {
+    fn get_block_count(&self) -> Result<u32, SourceError> {
+        self.get_text("/blocks/tip/height")?
+            .parse()
+            .map_err(|e: std::num::ParseIntError| EsploraError::Parse(e.to_string()).into())
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<String, SourceError> {
+        self.get_text(&format!("/block-height/{}", height))
+            .map_err(|err| map_404(err, SourceError::HeightOutOfRange(height)))
+    }
+
+    fn get_block(&self, block_hash: &str) -> Result<String, SourceError> {
+        self.get_bytes(&format!("/block/{}/raw", block_hash))
+            .map(hex::encode)
+            .map_err(|err| map_404(err, SourceError::NotFound(block_hash.to_string())))
+    }
+
+    fn get_previous_scripts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, SourceError> {
+        let block: EsploraBlock = self.get_json(&format!("/block/{}", block_hash))
+            .map_err(|err| map_404(err, SourceError::NotFound(block_hash.to_string())))?;
+
+        let mut previous_scripts = vec![];
+        for start_index in (0..block.tx_count).step_by(TXS_PAGE_SIZE) {
+            let transactions: Vec<EsploraTransaction> = self.get_json(&format!("/block/{}/txs/{}", block_hash, start_index))?;
+            for input in transactions.into_iter().flat_map(|tx| tx.vin) {
+                match (input.is_coinbase, input.prevout) {
+                    (false, Some(prevout)) => previous_scripts.push(PreviousScript::new(input.txid, input.vout, prevout.scriptpubkey)),
+                    _ => continue,
+                }
+            }
+        }
+        Ok(previous_scripts)
+    }
+
+    fn get_transaction(&self, txid: &str) -> Result<String, SourceError> {
+        self.get_text(&format!("/tx/{}/hex", txid))
+            .map_err(|err| map_404(err, SourceError::NotFound(txid.to_string())))
+    }
+}
+
+impl From<EsploraError> for SourceError {
+    fn from(err: EsploraError) -> Self {
+        SourceError::Esplora(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use bitcoin::consensus::encode::serialize;
+    use crate::fixture::{build_block, p2tr_script, taproot_spend};
+    use bitcoin::hashes::Hash;
+    use bitcoin::BlockHash;
+
+    // Serve canned responses keyed by request path until the test process exits
+    fn mock_server(routes: HashMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                BufReader::new(&stream).read_line(&mut request_line).unwrap();
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+                let (status, body) = match routes.get(path) {
+                    Some(body) => ("200 OK", body.clone()),
+                    None => ("404 Not Found", b"Block not found".to_vec()),
+                };
+                let header = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_esplora_source() {
+        let transactions: Vec<_> = (1..=30).map(|seed| taproot_spend(seed, vec![p2tr_script(seed + 100)])).collect();
+        let block = build_block(BlockHash::all_zeros(), 840000, transactions.iter().map(|(tx, _)| tx.clone()).collect());
+        let hash = block.block_hash().to_string();
+
+        // esplora's view of the block's transactions, coinbase first, in pages of 25
+        let mut txs_json: Vec<serde_json::Value> = vec![serde_json::json!({
+            "txid": block.txdata[0].compute_txid().to_string(),
+            "vin": [{ "txid": "0000000000000000000000000000000000000000000000000000000000000000", "vout": 4294967295u32, "is_coinbase": true, "prevout": null }],
+        })];
+        for (tx, prevout) in &transactions {
+            let input = &tx.input[0].previous_output;
+            txs_json.push(serde_json::json!({
+                "txid": tx.compute_txid().to_string(),
+                "vin": [{ "txid": input.txid.to_string(), "vout": input.vout, "is_coinbase": false, "prevout": { "scriptpubkey": serde_json::to_value(prevout).unwrap()["script"] } }],
+            }));
+        }
+
+        let mut routes = HashMap::from([
+            (String::from("/blocks/tip/height"), b"840000".to_vec()),
+            (String::from("/block-height/840000"), hash.clone().into_bytes()),
+            (format!("/block/{}", hash), serde_json::json!({ "id": hash, "tx_count": txs_json.len() }).to_string().into_bytes()),
+            (format!("/block/{}/raw", hash), serialize(&block)),
+        ]);
+        for (page, chunk) in txs_json.chunks(TXS_PAGE_SIZE).enumerate() {
+            routes.insert(format!("/block/{}/txs/{}", hash, page * TXS_PAGE_SIZE), serde_json::to_vec(chunk).unwrap());
+        }
+
+        let source = EsploraSource::new(EsploraConfig {
+            url: mock_server(routes),
+            timeout: Duration::from_secs(5),
+            retries: 0,
+        });
+
+        assert_eq!(source.get_block_count().unwrap(), 840000);
+        assert_eq!(source.get_block_hash(840000).unwrap(), hash);
+        assert!(matches!(source.get_block_hash(840001), Err(SourceError::HeightOutOfRange(840001))));
+        assert_eq!(source.get_block(&hash).unwrap(), hex::encode(serialize(&block)));
+        assert_eq!(
+            source.get_previous_scripts(&hash).unwrap(),
+            transactions.into_iter().map(|(_, prevout)| prevout).collect::<Vec<_>>()
+        );
+        assert!(matches!(source.get_transaction(&hash), Err(SourceError::NotFound(_))));
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Block, Header, Version};
+use bitcoin::consensus::encode::{deserialize_hex, serialize_hex};
+use bitcoin::hashes::Hash;
+use bitcoin::key::{Keypair, Secp256k1, TweakedPublicKey};
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::{Amount, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness};
+
+use serde_json::{json, Value};
+
+use crate::chain::PreviousScript;
+use crate::source::{ChainSource, SourceError};
+
+// Blocks written by `write_synthetic_fixtures`, not mined ones: they have regtest difficulty and
+// spend made up txids, only their heights are borrowed from mainnet's first taproot blocks.
+// `blocks/<height>.hex` with the scripts they spend in `blocks/<height>.prevouts.json`, and
+// `transactions/<txid>.hex` for previous transactions missing from those
+fn synthetic_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join("synthetic")
+}
+
+/// Chain source backed by blocks built in memory, so tests run without a node
+#[derive(Default)]
+pub struct MemorySource {
+    start_height: u32,
+    blocks: Vec<Block>,
+    previous_scripts: HashMap<String, Vec<PreviousScript>>,
+    transactions: HashMap<String, Transaction>,
+}
+
+impl MemorySource {
+    pub fn starting_at(start_height: u32) -> Self {
+        Self { start_height, ..Default::default() }
+    }
+
+    pub fn tip_hash(&self) -> BlockHash {
+        self.blocks.last().map_or(BlockHash::all_zeros(), |block| block.block_hash())
+    }
+
+    /// Append a block on top of the current tip
+    pub fn push_block(&mut self, block: Block, previous_scripts: Vec<PreviousScript>) {
+        self.previous_scripts.insert(block.block_hash().to_string(), previous_scripts);
+        self.blocks.push(block);
+    }
+
+    pub fn add_transaction(&mut self, transaction: Transaction) {
+        self.transactions.insert(transaction.compute_txid().to_string(), transaction);
+    }
+
+    /// Chain of the synthetic blocks in the fixtures directory
+    pub fn synthetic() -> Self {
+        let blocks_dir = synthetic_dir().join("blocks");
+        let mut heights: Vec<u32> = std::fs::read_dir(&blocks_dir).unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().to_str()?.strip_suffix(".hex")?.parse().ok())
+            .collect();
+        heights.sort();
+
+        let mut source = Self::starting_at(heights[0]);
+        for height in heights {
+            let block = deserialize_hex(std::fs::read_to_string(blocks_dir.join(format!("{}.hex", height))).unwrap().trim()).unwrap();
+            let previous_scripts = serde_json::from_str(&std::fs::read_to_string(blocks_dir.join(format!("{}.prevouts.json", height))).unwrap()).unwrap();
+            source.push_block(block, previous_scripts);
+        }
+        for entry in std::fs::read_dir(synthetic_dir().join("transactions")).unwrap() {
+            source.add_transaction(deserialize_hex(std::fs::read_to_string(entry.unwrap().path()).unwrap().trim()).unwrap());
+        }
+        source
+    }
+}
+
+impl ChainSource for MemorySource {
+    fn get_block_count(&self) -> Result<u32, SourceError> {
+        Ok((self.start_height + self.blocks.len() as u32).saturating_sub(1))
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<String, SourceError> {
+        height.checked_sub(self.start_height)
+            .and_then(|index| self.blocks.get(index as usize))
+            .map(|block| block.block_hash().to_string())
+            .ok_or(SourceError::HeightOutOfRange(height))
+    }
+
+    fn get_block(&self, block_hash: &str) -> Result<String, SourceError> {
+        self.blocks.iter()
+            .find(|block| block.block_hash().to_string() == block_hash)
+            .map(serialize_hex)
+            .ok_or_else(|| SourceError::NotFound(block_hash.to_string()))
+    }
+
+    fn get_previous_scripts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, SourceError> {
+        self.previous_scripts.get(block_hash)
+            .cloned()
+            .ok_or_else(|| SourceError::NotFound(block_hash.to_string()))
+    }
+
+    fn get_transaction(&self, txid: &str) -> Result<String, SourceError> {
+        self.transactions.get(txid)
+            .map(serialize_hex)
+            .ok_or_else(|| SourceError::NotFound(txid.to_string()))
+    }
+}
+
+/// Deterministic P2TR output script for `seed`
+pub fn p2tr_script(seed: u8) -> ScriptBuf {
+    let secp = Secp256k1::new();
+    let keypair = Keypair::from_secret_key(&secp, &SecretKey::from_slice(&[seed; 32]).unwrap());
+    ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(keypair.x_only_public_key().0))
+}
+
+/// Transaction spending `previous_output` via taproot key path to `outputs`
+pub fn spend(previous_output: OutPoint, outputs: Vec<ScriptBuf>) -> Transaction {
+    Transaction {
+        version: bitcoin::transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::from_slice(&[[1u8; 64]]),
+        }],
+        output: outputs.into_iter().map(|script_pubkey| TxOut { value: Amount::from_sat(10_000), script_pubkey }).collect(),
+    }
+}
+
+/// Transaction spending a (made up) P2TR output keyed by `seed` to `outputs`,
+/// returned with the previous script of its input
+pub fn taproot_spend(seed: u8, outputs: Vec<ScriptBuf>) -> (Transaction, PreviousScript) {
+    let previous_output = OutPoint { txid: Txid::from_byte_array([seed; 32]), vout: seed as u32 };
+    let previous_script = PreviousScript::new(previous_output.txid.to_string(), previous_output.vout, p2tr_script(seed).to_hex_string());
+    (spend(previous_output, outputs), previous_script)
+}
+
+/// Block on top of `prev_blockhash` holding a coinbase followed by `transactions`
+pub fn build_block(prev_blockhash: BlockHash, height: u32, transactions: Vec<Transaction>) -> Block {
+    let coinbase = Transaction {
+        version: bitcoin::transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::builder().push_int(height as i64).into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut { value: Amount::from_sat(50_000), script_pubkey: p2tr_script(0xcb) }],
+    };
+
+    let mut block = Block {
+        header: Header {
+            version: Version::TWO,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 1_700_000_000 + height,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata: std::iter::once(coinbase).chain(transactions).collect(),
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    block
+}
+
+/// JSON-RPC stand-in for bitcoind serving a `MemorySource`, so the indexer can be run end to end
+/// over RPC. Answers the calls the indexer makes and nothing else
+pub struct FakeNode {
+    pub url: String,
+    chain: Arc<(Mutex<MemorySource>, Condvar)>,
+    // calls still to be answered with RPC_IN_WARMUP, like a node that is starting up
+    warming_up: Arc<AtomicU32>,
+}
+
+impl FakeNode {
+    pub fn start(source: MemorySource) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let chain = Arc::new((Mutex::new(source), Condvar::new()));
+        let warming_up = Arc::new(AtomicU32::new(0));
+
+        let (node_chain, node_warming_up) = (chain.clone(), warming_up.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (chain, warming_up) = (node_chain.clone(), node_warming_up.clone());
+                // waitforblockheight holds its connection open, so serve each one on its own thread
+                std::thread::spawn(move || serve_rpc(stream.unwrap(), &chain, &warming_up));
+            }
+        });
+        Self { url, chain, warming_up }
+    }
+
+    /// Answer the next `calls` requests with RPC_IN_WARMUP, as bitcoind does while loading
+    pub fn warm_up(&self, calls: u32) {
+        self.warming_up.store(calls, Ordering::SeqCst);
+    }
+
+    /// Mine a block on top of the node's tip, waking any `waitforblockheight` callers
+    pub fn push_block(&self, transactions: Vec<Transaction>, previous_scripts: Vec<PreviousScript>) {
+        let (source, new_block) = &*self.chain;
+        let mut source = source.lock().unwrap();
+        let height = source.get_block_count().unwrap() + 1;
+        let block = build_block(source.tip_hash(), height, transactions);
+        source.push_block(block, previous_scripts);
+        new_block.notify_all();
+    }
+}
+
+fn serve_rpc(stream: TcpStream, chain: &(Mutex<MemorySource>, Condvar), warming_up: &AtomicU32) {
+    let mut reader = BufReader::new(&stream);
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    let request: Value = serde_json::from_slice(&body).unwrap();
+
+    let warming = warming_up.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |calls| calls.checked_sub(1)).is_ok();
+    let result = if warming {
+        Err((-28, String::from("Loading block index...")))
+    } else {
+        rpc_result(chain, request["method"].as_str().unwrap(), &request["params"])
+    };
+    let (status, response) = match result {
+        Ok(result) => ("200 OK", json!({ "result": result, "error": null, "id": request["id"] })),
+        Err((code, message)) => ("500 Internal Server Error", json!({ "result": null, "error": { "code": code, "message": message }, "id": request["id"] })),
+    };
+    let response = response.to_string();
+    let mut stream = &stream;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, response.len(), response).unwrap();
+}
+
+fn rpc_result(chain: &(Mutex<MemorySource>, Condvar), method: &str, params: &Value) -> Result<Value, (i32, String)> {
+    let (source, new_block) = chain;
+    let source = source.lock().unwrap();
+    let not_found = |err: SourceError| (-5, err.to_string());
+    match method {
+        "getblockcount" => Ok(json!(source.get_block_count().unwrap())),
+        "getblockhash" => source.get_block_hash(params[0].as_u64().unwrap() as u32)
+            .map(|hash| json!(hash))
+            .map_err(|_| (-8, String::from("Block height out of range"))),
+        "getblock" if params[1] == 0 => source.get_block(params[0].as_str().unwrap()).map(|hex| json!(hex)).map_err(not_found),
+        "getblock" => {
+            let block: Block = deserialize_hex(&source.get_block(params[0].as_str().unwrap()).map_err(not_found)?).unwrap();
+            let previous_scripts: Vec<Value> = source.get_previous_scripts(params[0].as_str().unwrap()).unwrap()
+                .iter().map(|script| serde_json::to_value(script).unwrap()).collect();
+            // verbosity 3, inputs carry their previous output when the node knows it
+            let tx: Vec<Value> = block.txdata.iter().map(|tx| {
+                let vin: Vec<Value> = tx.input.iter().map(|input| {
+                    if input.previous_output.is_null() {
+                        return json!({ "coinbase": "" });
+                    }
+                    let (txid, vout) = (input.previous_output.txid.to_string(), input.previous_output.vout);
+                    let mut vin = json!({ "txid": txid, "vout": vout });
+                    if let Some(script) = previous_scripts.iter().find(|script| script["txid"] == txid && script["vout"] == vout) {
+                        vin["prevout"] = json!({ "scriptPubKey": { "hex": script["script"] } });
+                    }
+                    vin
+                }).collect();
+                json!({ "txid": tx.compute_txid().to_string(), "vin": vin })
+            }).collect();
+            Ok(json!({ "hash": block.block_hash().to_string(), "tx": tx }))
+        }
+        "getrawtransaction" => source.get_transaction(params[0].as_str().unwrap()).map(|hex| json!(hex)).map_err(not_found),
+        "waitforblockheight" => {
+            let height = params[0].as_u64().unwrap() as u32;
+            let timeout = Duration::from_millis(params[1].as_u64().unwrap_or(0));
+            let (source, _) = new_block.wait_timeout_while(source, timeout, |source| source.get_block_count().unwrap() < height).unwrap();
+            let tip = source.get_block_count().unwrap();
+            Ok(json!({ "hash": source.get_block_hash(tip).unwrap(), "height": tip }))
+        }
+        method => Err((-32601, format!("Method not found: {}", method))),
+    }
+}
+
+// Rebuild the synthetic fixture blocks:
+// cargo test -- --ignored write_synthetic_fixtures
+#[test]
+#[ignore]
+fn write_synthetic_fixtures() {
+    let mut source = MemorySource::starting_at(709632);
+
+    // one tx paying a taproot output, one paying only an OP_RETURN
+    let (taproot_tx, taproot_prevout) = taproot_spend(1, vec![p2tr_script(2)]);
+    let (other_tx, other_prevout) = taproot_spend(3, vec![ScriptBuf::new_op_return([0u8; 4])]);
+    source.push_block(build_block(source.tip_hash(), 709632, vec![taproot_tx, other_tx]), vec![taproot_prevout, other_prevout]);
+
+    // nothing to tweak
+    let (other_tx, other_prevout) = taproot_spend(4, vec![ScriptBuf::new_op_return([0u8; 4])]);
+    source.push_block(build_block(source.tip_hash(), 709633, vec![other_tx]), vec![other_prevout]);
+
+    // the previous output isn't among the block's prevouts and has to be looked up by txid
+    let (funding_tx, _) = taproot_spend(5, vec![p2tr_script(6)]);
+    let spending_tx = spend(OutPoint { txid: funding_tx.compute_txid(), vout: 0 }, vec![p2tr_script(7)]);
+    source.add_transaction(funding_tx);
+    source.push_block(build_block(source.tip_hash(), 709634, vec![spending_tx]), vec![]);
+
+    let spends: Vec<_> = (8..=10).map(|seed| taproot_spend(seed, vec![p2tr_script(seed + 100)])).collect();
+    let block = build_block(source.tip_hash(), 709635, spends.iter().map(|(tx, _)| tx.clone()).collect());
+    source.push_block(block, spends.into_iter().map(|(_, prevout)| prevout).collect());
+
+    let blocks_dir = synthetic_dir().join("blocks");
+    let transactions_dir = synthetic_dir().join("transactions");
+    std::fs::create_dir_all(&blocks_dir).unwrap();
+    std::fs::create_dir_all(&transactions_dir).unwrap();
+    for height in 709632..=source.get_block_count().unwrap() {
+        let hash = source.get_block_hash(height).unwrap();
+        std::fs::write(blocks_dir.join(format!("{}.hex", height)), source.get_block(&hash).unwrap() + "\n").unwrap();
+        let previous_scripts = serde_json::to_string_pretty(&source.get_previous_scripts(&hash).unwrap()).unwrap();
+        std::fs::write(blocks_dir.join(format!("{}.prevouts.json", height)), previous_scripts + "\n").unwrap();
+    }
+    for (txid, tx) in &source.transactions {
+        std::fs::write(transactions_dir.join(format!("{}.hex", txid)), serialize_hex(tx) + "\n").unwrap();
+    }
+}
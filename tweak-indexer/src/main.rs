@@ -1,34 +1,127 @@
-use std::{process::exit, thread::sleep, time::Duration};
-use clap::Parser;
+use std::{collections::BTreeMap, error::Error, path::{Path, PathBuf}, process::exit, sync::{atomic::{AtomicBool, Ordering}, mpsc, Mutex}, thread, time::{Duration, Instant}};
+use clap::{CommandFactory, Parser, Subcommand};
 use database::Database;
+use blockfiles::BlockFileSource;
+use esplora::{EsploraConfig, EsploraSource};
+use rpc::{RpcAuth, RpcClient, RpcConfig};
+use source::{sleep_unless_stopped, ChainSource, SourceError, STOP_CHECK_INTERVAL};
 use tracing::{error, info, warn, Level};
 use tracing_subscriber::{filter, fmt, layer::SubscriberExt, EnvFilter, Layer, Registry};
 use tracing_appender::rolling;
+use tweak_common::config::Config;
+use tweak_common::network::Network;
 
+mod blockfiles;
 mod chain;
 mod database;
+mod esplora;
+#[cfg(test)]
+mod fixture;
+mod rpc;
+mod source;
+
+// Exit code of `range` when the source's tip is below --to, errors exit with 1 and bad arguments with 2
+const EXIT_TIP_BELOW_RANGE: i32 = 3;
+// Exit code after stopping on SIGINT or SIGTERM
+const EXIT_INTERRUPTED: i32 = 130;
+
+// Longest a single long poll holds its request, so a shutdown is noticed while waiting for a block
+const WAIT_SLICE: Duration = Duration::from_secs(5);
 
 #[derive(Parser)]
 #[command(long_about)]
 struct Cli {
-    #[arg(long)]
-    start_height: Option<u32>,
-    #[arg(long)]
-    end_height: Option<u32>,
-    #[arg(long)]
-    blocks: Option<u32>,
+    /// What to index, `follow` when none is given
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML config file, the flags below override it (default: tweak.toml if it exists)
+    #[arg(long, global = true, env = "TWEAK_CONFIG")]
+    config: Option<PathBuf>,
+    /// Print the effective configuration as TOML and exit
+    #[arg(long, global = true)]
+    print_config: bool,
+    /// SQLite database the tweaks are written to (default: blocks.db)
+    #[arg(long, global = true, env = "TWEAK_DB")]
+    db: Option<String>,
+    /// mainnet, testnet, testnet4, signet or regtest, sets the default start height and RPC port (default: mainnet)
+    #[arg(long, global = true, env = "TWEAK_NETWORK")]
+    network: Option<Network>,
+    /// Directory for the daily debug log (default: logs)
+    #[arg(long, global = true, env = "TWEAK_LOG_DIR")]
+    log_dir: Option<PathBuf>,
+    /// Bitcoin Core RPC endpoint (default: http://127.0.0.1 on the network's RPC port)
+    #[arg(long, global = true, env = "TWEAK_RPC_URL")]
+    rpc_url: Option<String>,
+    /// Cookie file used for RPC auth when no user is given (default: the network's .cookie under ~/.bitcoin)
+    #[arg(long, global = true, env = "TWEAK_RPC_COOKIE")]
+    rpc_cookie: Option<PathBuf>,
+    #[arg(long, global = true, env = "TWEAK_RPC_USER", requires = "rpc_password")]
+    rpc_user: Option<String>,
+    #[arg(long, global = true, env = "TWEAK_RPC_PASSWORD", requires = "rpc_user")]
+    rpc_password: Option<String>,
+    /// RPC (or esplora) request timeout in seconds (default: 60)
+    #[arg(long, global = true, env = "TWEAK_RPC_TIMEOUT")]
+    rpc_timeout: Option<u64>,
+    /// Number of times to retry an RPC (or esplora) call when the node cannot be reached (default: 3)
+    #[arg(long, global = true, env = "TWEAK_RPC_RETRIES")]
+    rpc_retries: Option<u32>,
+    /// Index offline from a Bitcoin Core `blocks` directory (blk*.dat / rev*.dat) instead of RPC
+    #[arg(long, global = true, env = "TWEAK_BLOCKS_DIR", conflicts_with = "esplora_url")]
+    blocks_dir: Option<PathBuf>,
+    /// Index from an electrs/esplora REST API (e.g. http://127.0.0.1:3000) instead of RPC
+    #[arg(long, global = true, env = "TWEAK_ESPLORA_URL")]
+    esplora_url: Option<String>,
+    /// Seconds to wait for a new block before checking again when following the tip (default: 300)
+    #[arg(long, global = true, env = "TWEAK_POLL_INTERVAL", value_parser = clap::value_parser!(u64).range(1..))]
+    poll_interval: Option<u64>,
+    /// Deepest chain reorganization to unwind automatically before giving up (default: 100)
+    #[arg(long, global = true, env = "TWEAK_REORG_DEPTH")]
+    reorg_depth: Option<u32>,
+    /// Blocks fetched and processed in parallel (default: number of CPUs)
+    #[arg(long, global = true, env = "TWEAK_JOBS", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: Option<u32>,
+}
+
+#[derive(Subcommand, Clone, Copy, Debug, PartialEq)]
+enum Command {
+    /// Index from the last stored block (or the network's first taproot block) up to the tip, then exit
+    Sync,
+    /// Sync, then index each new block as it arrives
+    Follow {
+        /// Height to start from instead of the last stored block
+        #[arg(long)]
+        from: Option<u32>,
+    },
+    /// Index the blocks from --from to --to inclusive then exit, with 3 if the tip is below --to
+    Range {
+        #[arg(long)]
+        from: u32,
+        #[arg(long)]
+        to: u32,
+    },
+    /// Drop every block stored from --height up, with its tweaks, and index them again up to the tip
+    Reindex {
+        #[arg(long)]
+        height: u32,
+    },
 }
 
 struct StartupParams {
-    start_height: u32,
-    end_height: u32,
-    continuous_index: bool,
+    command: Command,
     db_path: String,
+    network: Network,
+    log_dir: PathBuf,
+    rpc: RpcConfig,
+    blocks_dir: Option<PathBuf>,
+    esplora_url: Option<String>,
+    poll_interval: Duration,
+    reorg_depth: u32,
+    jobs: usize,
 }
 
-fn setup_logging() {
+fn setup_logging(log_dir: &Path) {
     // Create a rolling file appender (daily logs)
-    let file_appender = rolling::daily("logs", "debug.log");
+    let file_appender = rolling::daily(log_dir, "debug.log");
 
     // let file_appender = File::create("debug.log");
     // let file_appender = match file_appender  {Ok(file) => file,Err(error) => panic!("Error: {:?}",error),};
@@ -53,21 +146,21 @@ fn setup_logging() {
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set global subscriber");
 }
 
-fn auto_index(db: &Database) -> (u32, u32) {
+fn auto_index(db: &Database, source: &dyn ChainSource, network: Network, stop: &AtomicBool) -> Result<(u32, u32), Halt> {
 
-    let starting_block= db.get_highest_block().map_or_else(
-        |err| {
-            error!("Failed to fetch highest block: {}", err);
-            exit(1);
-        },
-        |highest_block| if highest_block > 0 { highest_block } else { 709632 }, //Default to first Taproot block
-    );
+    let highest_block = db.get_highest_block()
+        .map_err(|err| Halt::Failed(format!("Failed to fetch highest block: {}", err)))?;
+    let starting_block = if highest_block > 0 { highest_block } else { network.taproot_start_height() };
 
-    let mut last_block = match chain::get_block_count() {
-        Ok(block_count) => block_count.parse().expect("Failed to parse current block count"),
-        Err(err) => {
-            error!("Error fetching block count: {}", err);
-            exit(1);
+    let mut attempt = 0;
+    let mut last_block = loop {
+        match source.get_block_count() {
+            Ok(block_count) => break block_count,
+            Err(err) if err.is_transient() => {
+                warn!("Error fetching block count: {}", err);
+                backoff(stop, &mut attempt)?;
+            }
+            Err(err) => return Err(Halt::Failed(format!("Error fetching block count: {}", err))),
         }
     };
 
@@ -75,12 +168,79 @@ fn auto_index(db: &Database) -> (u32, u32) {
         last_block = starting_block
     }
 
-    (starting_block, last_block)
+    Ok((starting_block, last_block))
+}
+
+fn default_cookie_path(network: Network) -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_default();
+    let datadir = PathBuf::from(home).join(".bitcoin");
+    match network.data_subdir() {
+        Some(subdir) => datadir.join(subdir).join(".cookie"),
+        None => datadir.join(".cookie"),
+    }
+}
+
+// The config file with the flags given on the command line applied on top, and the defaults
+// that depend on other settings filled in
+fn load_config(cli: &Cli) -> Config {
+    let mut config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
+
+    if let Some(db) = &cli.db { config.db = db.clone(); }
+    if let Some(network) = cli.network { config.network = network; }
+    if let Some(log_dir) = &cli.log_dir { config.log_dir = log_dir.clone(); }
+    if let Some(url) = &cli.rpc_url { config.rpc.url = Some(url.clone()); }
+    if let Some(cookie) = &cli.rpc_cookie {
+        // a cookie on the command line wins over credentials in the file
+        config.rpc = tweak_common::config::RpcSettings { cookie: Some(cookie.clone()), user: None, password: None, ..config.rpc };
+    }
+    if let (Some(user), Some(password)) = (&cli.rpc_user, &cli.rpc_password) {
+        config.rpc.user = Some(user.clone());
+        config.rpc.password = Some(password.clone());
+    }
+    if let Some(timeout) = cli.rpc_timeout { config.rpc.timeout = timeout; }
+    if let Some(retries) = cli.rpc_retries { config.rpc.retries = retries; }
+    if let Some(blocks_dir) = &cli.blocks_dir { config.indexer.blocks_dir = Some(blocks_dir.clone()); }
+    if let Some(esplora_url) = &cli.esplora_url { config.indexer.esplora_url = Some(esplora_url.clone()); }
+    if let Some(poll_interval) = cli.poll_interval { config.indexer.poll_interval = poll_interval; }
+    if let Some(reorg_depth) = cli.reorg_depth { config.indexer.reorg_depth = reorg_depth; }
+    if let Some(jobs) = cli.jobs { config.indexer.jobs = Some(jobs); }
+    if config.indexer.jobs == Some(0) {
+        eprintln!("jobs must be at least 1");
+        exit(1);
+    }
+    // at 0 following would ask the node for a new block in a busy loop
+    if config.indexer.poll_interval == 0 {
+        eprintln!("poll_interval must be at least 1 second");
+        exit(1);
+    }
+
+    let network = config.network;
+    config.rpc.url.get_or_insert_with(|| format!("http://127.0.0.1:{}", network.rpc_port()));
+    if config.rpc.user.is_none() || config.rpc.password.is_none() {
+        config.rpc.cookie.get_or_insert_with(|| default_cookie_path(network));
+    }
+    config.indexer.jobs.get_or_insert_with(|| thread::available_parallelism().map_or(1, |n| n.get() as u32));
+    config
 }
 
 fn handle_inputs() -> StartupParams {
 
     let cli = Cli::parse();
+    let config = load_config(&cli);
+    if cli.print_config {
+        print!("{}", config.to_toml());
+        exit(0);
+    }
+    if config.indexer.blocks_dir.is_some() && config.indexer.esplora_url.is_some() {
+        eprintln!("Only one of blocks_dir and esplora_url can be set");
+        exit(1);
+    }
 
     // let silent_address = if let Some(silent_str) = cli.silent.as_deref() {
     //     SilentPaymentAddress::try_from(silent_str).expect("invalid silent address input provided")
@@ -96,139 +256,671 @@ fn handle_inputs() -> StartupParams {
     //     println!("Scan Pub Key (Hex): {}", hex::encode(scan_pubkey.serialize()));
     //     SilentPaymentAddress::new(scan_pubkey, spend_pubkey, Network::Mainnet, 0).unwrap()
     // };
-    let start_height = if let Some(height) = cli.start_height {
-        height
-    } else {
-        0
-    };
+    let command = cli.command.unwrap_or(Command::Follow { from: None });
+    if let Command::Range { from, to } = command {
+        if from > to {
+            Cli::command().error(clap::error::ErrorKind::ValueValidation, "range --from must not be above --to").exit();
+        }
+    }
 
-    let end_height = if let Some(height) = cli.end_height {
-        height
-    } else {
-        let block_count = if let Some(count) = cli.blocks {
-            count
-        } else {
-            10
-        };
-        start_height + block_count
+    let rpc = config.rpc;
+    let auth = match (rpc.user, rpc.password, rpc.cookie) {
+        (Some(user), Some(password), _) => RpcAuth::UserPass(user, password),
+        (_, _, cookie) => RpcAuth::Cookie(cookie.unwrap_or_else(|| default_cookie_path(config.network))),
     };
 
     StartupParams{ 
-        start_height: start_height, 
-        end_height: end_height, 
-        continuous_index: start_height == 0, 
-        db_path: String::from("blocks.db"),
+        command,
+        db_path: config.db,
+        network: config.network,
+        log_dir: config.log_dir,
+        rpc: RpcConfig {
+            url: rpc.url.unwrap_or_default(),
+            auth,
+            timeout: Duration::from_secs(rpc.timeout),
+            retries: rpc.retries,
+        },
+        blocks_dir: config.indexer.blocks_dir,
+        esplora_url: config.indexer.esplora_url,
+        poll_interval: Duration::from_secs(config.indexer.poll_interval),
+        reorg_depth: config.indexer.reorg_depth,
+        jobs: config.indexer.jobs.unwrap_or(1) as usize,
     }
 }
 
-fn index_blocks(startup: StartupParams) {
+// Walk back from `height`, whose stored block is no longer on the source's active chain, to the
+// last stored block that still is. Everything above it is removed and the height to re-index from returned
+fn rollback_reorg(db: &Database, source: &dyn ChainSource, height: u32, reorg_depth: u32) -> Result<u32, Box<dyn Error>> {
+    let mut resume_height = height;
+    while resume_height > 0 {
+        let below = resume_height - 1;
+        let still_active = match db.get_block_by_height(below)? {
+            Some(stored) => stored.hash == source.get_block_hash(below)?,
+            None => true,
+        };
+        if still_active {
+            break;
+        }
+        if height - below >= reorg_depth {
+            return Err(format!("reorg at height {} is deeper than {} blocks", height, reorg_depth).into());
+        }
+        resume_height = below;
+    }
 
-    let db = match database::Database::new(&startup.db_path) {
-        Ok(db) => db,
-        Err(err) => {
-            error!("Not able to open database: {}", err);
-            exit(1);
+    warn!("Chain reorganization at height {}, rolling back to height {}", height, resume_height);
+    for orphaned in resume_height..=db.get_highest_block()? {
+        info!("Dropping {} tweaks at height {}", db.get_tweaks_by_height(orphaned)?.len(), orphaned);
+    }
+    db.remove_blocks_from(resume_height)?;
+    Ok(resume_height)
+}
+
+// Why indexing stopped short
+#[derive(Debug)]
+enum Halt {
+    // SIGINT or SIGTERM, every block stored so far is complete
+    Interrupted,
+    Failed(String),
+}
+
+// Wait before trying an unreachable source again, 1, 2, 4 .. up to 64 seconds
+fn backoff(stop: &AtomicBool, attempt: &mut u32) -> Result<(), Halt> {
+    let delay = Duration::from_secs(1 << (*attempt).min(6));
+    *attempt += 1;
+    info!("Retrying in {} seconds", delay.as_secs());
+    if sleep_unless_stopped(stop, delay) { Ok(()) } else { Err(Halt::Interrupted) }
+}
+
+// Return as soon as the source reports `height` has been mined, or sleep out the poll interval
+// for sources that cannot notify us
+fn wait_for_next_block(source: &dyn ChainSource, height: u32, poll_interval: Duration, stop: &AtomicBool) -> Result<(), Halt> {
+    let deadline = Instant::now() + poll_interval;
+    while !stop.load(Ordering::Relaxed) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            info!("No new block after {} seconds, checking again", poll_interval.as_secs());
+            return Ok(());
+        }
+        let slept = match source.wait_for_block(height, remaining.min(WAIT_SLICE)) {
+            Ok(Some(tip)) if tip >= height => {
+                info!("New block at height {}", tip);
+                return Ok(());
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) => {
+                info!("Sleeping for {} seconds, then try again", remaining.as_secs());
+                sleep_unless_stopped(stop, remaining)
+            }
+            Err(err) => {
+                warn!("Waiting for block {} failed, sleeping for {} seconds: {}", height, remaining.as_secs(), err);
+                sleep_unless_stopped(stop, remaining)
+            }
+        };
+        if slept {
+            return Ok(());
         }
+    }
+    Err(Halt::Interrupted)
+}
+
+// What a sync worker found at one height, committed in height order
+enum Fetched {
+    // past the source's tip
+    Tip,
+    // already stored with this hash
+    Stored(String),
+    Processed { hash: String, header: bitcoin::block::Header, tx_count: u32, tweaks: Vec<database::Tweak>, filter: Vec<u8> },
+    // fetched but not processable, indexing halts before it
+    Failed { hash: String, err: String },
+}
+
+// Where `sync_blocks` stopped
+enum Synced {
+    // next height to index
+    Until(u32),
+    // a reorg was rolled back, fetching starts over from here
+    RolledBack(u32),
+    // the source could not be reached for this height, fetching starts over from it after a backoff
+    Unreachable { height: u32, err: SourceError },
+}
+
+// Fetch and process the block at `height` on a worker, unless it's already stored as `stored_hash`
+fn fetch_block(source: &dyn ChainSource, height: u32, stored_hash: Option<String>) -> Result<Fetched, SourceError> {
+    let hash = match source.get_block_hash(height) {
+        Ok(hash) => hash,
+        Err(SourceError::HeightOutOfRange(_)) => return Ok(Fetched::Tip),
+        Err(err) => return Err(err),
     };
+    if stored_hash.as_ref() == Some(&hash) {
+        return Ok(Fetched::Stored(hash));
+    }
+
+    let block_hex = source.get_block(&hash)?;
+    let previous_scripts = source.get_previous_scripts(&hash)?;
+
+    info!("Processing block hash {}, height: {}", hash, height);
+    let mut chain = chain::Chain::new(source);
+    chain.set_previous_scripts(previous_scripts);
+    let processed = chain.process_transactions(&block_hex, height)
+        .and_then(|tweaks| Ok((chain.taproot_filter(&tweaks)?, tweaks)));
+    match processed {
+        Ok((filter, tweaks)) => {
+            let block = chain.get_block();
+            Ok(Fetched::Processed { hash, header: block.header, tx_count: block.txdata.len() as u32, tweaks, filter })
+        }
+        Err(err) => match err.downcast::<SourceError>() {
+            Ok(err) => Err(*err),
+            Err(err) => Ok(Fetched::Failed { hash, err: err.to_string() }),
+        },
+    }
+}
 
-    let mut current_block = startup.start_height;
-    let mut last_block = startup.end_height;
-    
+// Index `height..=last_block`. `jobs` workers fetch and process blocks ahead of the committer,
+// which stores them in height order so the database never has gaps. Returns the next height to index
+fn sync_blocks(db: &Database, source: &dyn ChainSource, mut height: u32, last_block: u32, jobs: usize, reorg_depth: u32, stop: &AtomicBool) -> Result<u32, Halt> {
+    let mut attempt = 0;
     loop {
-        // determine next block based on last block processed in db
-        if startup.continuous_index {
-            (current_block, last_block) = auto_index(&db);
-        }
-
-        let mut chain = chain::Chain::new(&db);
-        while current_block <= last_block {
-            let block_hash = match chain::get_block_hash(current_block) {
-                Ok(block_hash_str) => block_hash_str,
-                Err(err) => {
-                    if err.contains("height out of range") {
-                        info!("At current block height");
-                        break;
-                    } else {
-                        error!("Error fetching block hash: {}", err);
-                        exit(1);
-                    }
+        match sync_batch(db, source, height, last_block, jobs, reorg_depth, stop)? {
+            Synced::Until(next) => return Ok(next),
+            Synced::RolledBack(resume_height) => height = resume_height,
+            Synced::Unreachable { height: failed, err } => {
+                // blocks stored since the last failure start the backoff over
+                if failed > height {
+                    attempt = 0;
                 }
-            };
+                warn!("Error fetching block {}: {}", failed, err);
+                backoff(stop, &mut attempt)?;
+                height = failed;
+            }
+        }
+    }
+}
 
-            // check if the block has been handled
-            if db.get_block(&block_hash).is_ok_and(|x| x.len() > 0) {
-                info!("******** Already processed block hash {}, height: {} ********", block_hash, current_block);
-                current_block += 1;
-                continue;
+fn sync_batch(db: &Database, source: &dyn ChainSource, mut height: u32, last_block: u32, jobs: usize, reorg_depth: u32, stop: &AtomicBool) -> Result<Synced, Halt> {
+    // blocks in flight, bounds how far workers run ahead of the committer
+    let window = jobs * 2;
+
+    let (job_tx, job_rx) = mpsc::channel::<(u32, Option<String>)>();
+    let job_rx = Mutex::new(job_rx);
+
+    thread::scope(|scope| {
+        // owned by the committer, dropping it on return stops the workers
+        let job_tx = job_tx;
+        let (result_tx, result_rx) = mpsc::channel();
+        for _ in 0..jobs {
+            let job_rx = &job_rx;
+            let result_tx = result_tx.clone();
+            scope.spawn(move || loop {
+                let job = job_rx.lock().unwrap().recv();
+                let Ok((height, stored_hash)) = job else { break };
+                if result_tx.send((height, fetch_block(source, height, stored_hash))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(result_tx);
+
+        let dispatch = |next: u32| {
+            let stored_hash = db.get_block_by_height(next).ok().flatten().map(|block| block.hash);
+            let _ = job_tx.send((next, stored_hash));
+        };
+        let mut next_job = height;
+        while next_job <= last_block && next_job < height.saturating_add(window as u32) {
+            dispatch(next_job);
+            next_job += 1;
+        }
+
+        let mut fetched = BTreeMap::new();
+        while height <= last_block {
+            // checked between blocks, so the one being stored always commits
+            if stop.load(Ordering::Relaxed) {
+                return Err(Halt::Interrupted);
             }
+            let Some(result) = fetched.remove(&height) else {
+                match result_rx.recv_timeout(STOP_CHECK_INTERVAL) {
+                    Ok((fetched_height, result)) => {
+                        fetched.insert(fetched_height, result);
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    // blocks are left to fetch, so this isn't the tip
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Err(Halt::Failed(format!("Every sync worker stopped before block {}", height))),
+                }
+                continue;
+            };
 
-            let block_hex = match chain::get_block(&block_hash) {
-                Ok(block_str) => block_str,
-                Err(err) => {
-                    error!("Error fetching block: {}", err);
-                    exit(1);
+            let (hash, processed) = match result {
+                Ok(Fetched::Tip) => {
+                    info!("At current block height");
+                    break;
                 }
+                Ok(Fetched::Stored(hash)) => {
+                    info!("******** Already processed block hash {}, height: {} ********", hash, height);
+                    (hash, None)
+                }
+                Ok(Fetched::Processed { hash, header, tx_count, tweaks, filter }) => (hash, Some((header, tx_count, tweaks, filter))),
+                // skipping it would leave a gap that later passes, resuming from the highest block, never fill
+                Ok(Fetched::Failed { hash, err }) => return Err(Halt::Failed(format!("Error processing block {}, height {}: {}", hash, height, err))),
+                Err(err) if err.is_transient() => return Ok(Synced::Unreachable { height, err }),
+                Err(err) => return Err(Halt::Failed(format!("Error fetching block {}: {}", height, err))),
             };
 
-            match chain::get_block_input_transactions(&block_hash) {
-                Ok(prev_scripts) => chain.set_previous_scripts(prev_scripts),
-                Err(err) => {
-                    error!("Error fetching prev out scripts: {}", err);
-                    exit(1);
+            // a different block stored at this height, or one this block doesn't build on, means the
+            // chain reorganized under us
+            let stored_at = |at: u32| db.get_block_by_height(at).ok().flatten().map(|block| block.hash);
+            let builds_on_stored = match (&processed, height.checked_sub(1).and_then(stored_at)) {
+                (Some((header, _, _, _)), Some(below)) => header.prev_blockhash.to_string() == below,
+                _ => true,
+            };
+            if stored_at(height).is_some_and(|stored| stored != hash) || !builds_on_stored {
+                return match rollback_reorg(db, source, height, reorg_depth) {
+                    Ok(resume_height) => Ok(Synced::RolledBack(resume_height)),
+                    // nothing is removed until the fork point is found, so the reorg is simply seen again
+                    Err(err) => match err.downcast::<SourceError>() {
+                        Ok(err) if err.is_transient() => Ok(Synced::Unreachable { height, err: *err }),
+                        Ok(err) => Err(Halt::Failed(format!("Unable to roll back chain reorganization: {}", err))),
+                        Err(err) => Err(Halt::Failed(format!("Unable to roll back chain reorganization: {}", err))),
+                    },
+                };
+            }
+
+            if let Some((header, tx_count, tweaks, filter)) = processed {
+                let stored = db.insert_block(&database::Block { 
+                    height, 
+                    hash, 
+                    has_tweaks: !tweaks.is_empty(),
+                    prev_blockhash: Some(header.prev_blockhash.to_string()),
+                    timestamp: Some(header.time),
+                    merkle_root: Some(header.merkle_root.to_string()),
+                    tx_count: Some(tx_count),
+                    filter: Some(filter),
+                }, &tweaks);
+                if let Err(err) = stored {
+                    return Err(Halt::Failed(format!("Error storing block: {}", err)));
                 }
             }
-            
-            info!("Processing block hash {}, height: {}", block_hash, current_block);
-
-            match chain.process_transactions(&block_hex) {
-                Ok(has_tweaks) => {
-                    let _ = db.insert_block(&database::Block { 
-                        height: current_block, 
-                        hash: block_hash, 
-                        has_tweaks: has_tweaks 
-                    });
-                },
-                Err(err) => warn!("Not storing block: {}", err)
+
+            height += 1;
+            if next_job <= last_block {
+                dispatch(next_job);
+                next_job += 1;
             }
-            current_block += 1;
         }
+        Ok(Synced::Until(height))
+    })
+}
+
+// One pass of continuous mode, index from the highest stored block up to the source's tip and
+// return the next height to wait for
+fn catch_up(db: &Database, source: &dyn ChainSource, startup: &StartupParams, stop: &AtomicBool) -> Result<u32, Halt> {
+    let (current_block, last_block) = auto_index(db, source, startup.network, stop)?;
+    sync_blocks(db, source, current_block, last_block, startup.jobs, startup.reorg_depth, stop)
+}
+
+// Index to the tip, from `from` when given, then every new block until stopped
+fn follow(db: &Database, source: &dyn ChainSource, startup: &StartupParams, from: Option<u32>, stop: &AtomicBool) -> Result<i32, Halt> {
+    let mut next_block = match from {
+        Some(from) => {
+            let (_, last_block) = auto_index(db, source, startup.network, stop)?;
+            sync_blocks(db, source, from, last_block, startup.jobs, startup.reorg_depth, stop)?
+        }
+        None => catch_up(db, source, startup, stop)?,
+    };
+    loop {
+        wait_for_next_block(source, next_block, startup.poll_interval, stop)?;
+        next_block = catch_up(db, source, startup, stop)?;
+    }
+}
+
+// Run the indexing `startup.command` asks for until done, failed or `stop` is set, returning the
+// process exit code. The database is closed either way
+fn index_blocks(startup: StartupParams, source: &dyn ChainSource, stop: &AtomicBool) -> i32 {
 
-        if startup.continuous_index {
-            info!("Sleeping for 5 minutes, then try again");
-            sleep(Duration::from_secs(300));
-        } else {
-            db.close();
-            return;
+    let db = match database::Database::new(&startup.db_path) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Not able to open database: {}", err);
+            return 1;
         }
+    };
+    if let Err(err) = db.check_network(startup.network) {
+        error!("Not able to use database {}: {}", startup.db_path, err);
+        db.close();
+        return 1;
     }
 
+    let result = match startup.command {
+        Command::Sync => catch_up(&db, source, &startup, stop).map(|_| 0),
+        Command::Range { from, to } => sync_blocks(&db, source, from, to, startup.jobs, startup.reorg_depth, stop)
+            .map(|next_block| {
+                if next_block <= to {
+                    warn!("Tip is at height {}, below the end of the range {}", next_block - 1, to);
+                    EXIT_TIP_BELOW_RANGE
+                } else {
+                    0
+                }
+            }),
+        Command::Reindex { height } => db.remove_blocks_from(height)
+            .map_err(|err| Halt::Failed(format!("Not able to remove blocks from height {}: {}", height, err)))
+            .and_then(|_| auto_index(&db, source, startup.network, stop))
+            .and_then(|(_, last_block)| sync_blocks(&db, source, height, last_block, startup.jobs, startup.reorg_depth, stop))
+            .map(|_| 0),
+        Command::Follow { from } => follow(&db, source, &startup, from, stop),
+    };
+    db.close();
+
+    match result {
+        Ok(exit_code) => exit_code,
+        Err(Halt::Interrupted) => {
+            info!("Stopped, the database is closed");
+            EXIT_INTERRUPTED
+        }
+        Err(Halt::Failed(err)) => {
+            error!("{}", err);
+            1
+        }
+    }
 }
 
 fn main() {
-    setup_logging();
-    index_blocks( handle_inputs());
+    let startup = handle_inputs();
+    setup_logging(&startup.log_dir);
+
+    // The first SIGINT or SIGTERM lets the block being stored commit, a second exits right away and
+    // SQLite rolls back whatever was being written
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let handler = ctrlc::set_handler(|| {
+        if STOP.swap(true, Ordering::Relaxed) {
+            exit(EXIT_INTERRUPTED);
+        }
+        info!("Shutting down after the current block, interrupt again to exit now");
+    });
+    if let Err(err) = handler {
+        error!("Not able to install signal handler: {}", err);
+        exit(1);
+    }
+    let source: Box<dyn ChainSource> = match (&startup.blocks_dir, &startup.esplora_url) {
+        (Some(blocks_dir), _) => match BlockFileSource::open(blocks_dir) {
+            Ok(source) => Box::new(source),
+            Err(err) => {
+                error!("Not able to read block files: {}", err);
+                exit(1);
+            }
+        },
+        (None, Some(esplora_url)) => Box::new(EsploraSource::new(EsploraConfig {
+            url: esplora_url.clone(),
+            timeout: startup.rpc.timeout,
+            retries: startup.rpc.retries,
+        }).stop_on(&STOP)),
+        (None, None) => Box::new(RpcClient::new(startup.rpc.clone()).stop_on(&STOP)),
+    };
+    exit(index_blocks(startup, source.as_ref(), &STOP));
 }
 
 #[cfg(test)]
 mod tests {
     use crate::database;
-    use crate::chain::{Chain,get_block_with_input};
+    use crate::chain::Chain;
+    use crate::{catch_up, index_blocks, rollback_reorg, sync_blocks, wait_for_next_block, Command, Halt, StartupParams, EXIT_TIP_BELOW_RANGE};
+    use crate::fixture::{build_block, p2tr_script, taproot_spend, FakeNode, MemorySource};
+    use crate::rpc::{RpcAuth, RpcClient, RpcConfig};
+    use tweak_common::network::Network;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+    use crate::chain::PreviousScript;
+    use crate::rpc::RpcError;
+    use crate::source::{ChainSource, SourceError};
+
+    // Can't be asked for the hash at `height` the first `failures` times
+    struct Restarting {
+        source: MemorySource,
+        height: u32,
+        failures: AtomicU32,
+    }
+
+    impl ChainSource for Restarting {
+        fn get_block_count(&self) -> Result<u32, SourceError> { self.source.get_block_count() }
+        fn get_block_hash(&self, height: u32) -> Result<String, SourceError> {
+            if height == self.height && self.failures.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok() {
+                return Err(SourceError::Rpc(RpcError::Transport(String::from("connection refused"))));
+            }
+            self.source.get_block_hash(height)
+        }
+        fn get_block(&self, block_hash: &str) -> Result<String, SourceError> { self.source.get_block(block_hash) }
+        fn get_previous_scripts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, SourceError> { self.source.get_previous_scripts(block_hash) }
+        fn get_transaction(&self, txid: &str) -> Result<String, SourceError> { self.source.get_transaction(txid) }
+    }
 
     #[test]
     fn test_process_transactions() {
+        // 709632 has one taproot paying tx and one paying only an OP_RETURN, 709633 nothing to tweak,
+        // 709634 spends an output missing from its prevouts which is looked up through the source
+        let source = MemorySource::synthetic();
+
+        let mut chain = Chain::new(&source);
+        for (height, expect_tweaks) in [(709632, 1), (709633, 0), (709634, 1)] {
+            let block_hash = source.get_block_hash(height).unwrap();
+            chain.set_previous_scripts(source.get_previous_scripts(&block_hash).unwrap());
+            let tweaks = chain.process_transactions(&source.get_block(&block_hash).unwrap(), height).unwrap();
+            assert_eq!(tweaks.len(), expect_tweaks, "height {}", height);
+            assert!(tweaks.iter().all(|tweak| tweak.block_hash == block_hash && tweak.height == height));
+        }
+    }
+
+    #[test]
+    fn test_rollback_reorg() {
         let db = database::Database::new(":memory:").unwrap();
-        let mut chain = Chain::new(&db);
 
-        let block_hash = "0000000000000000000149ba526848af34e4dbed814a85859753fadf5594e226";
+        // stored chain 100..=104, the node's chain forks off after 101
+        let mut stored = MemorySource::starting_at(100);
+        let mut active = MemorySource::starting_at(100);
+        for height in 100..=101 {
+            let block = build_block(stored.tip_hash(), height, vec![]);
+            stored.push_block(block.clone(), vec![]);
+            active.push_block(block, vec![]);
+        }
+        for height in 102..=104 {
+            stored.push_block(build_block(stored.tip_hash(), height, vec![]), vec![]);
+            let (tx, prevout) = taproot_spend(height as u8, vec![p2tr_script(1)]);
+            active.push_block(build_block(active.tip_hash(), height, vec![tx]), vec![prevout]);
+        }
+        for height in 100..=104 {
+            db.insert_block(&database::Block {
+                height,
+                hash: stored.get_block_hash(height).unwrap(),
+                has_tweaks: false,
+                prev_blockhash: None,
+                timestamp: None,
+                merkle_root: None,
+                tx_count: None,
+                filter: None,
+            }, &[]).unwrap();
+        }
 
-        let block_hex = match get_block_with_input(&block_hash) {
-            Ok(block_str) => block_str,
-            Err(err) => {
-                err
+        assert!(rollback_reorg(&db, &active, 104, 2).is_err());
+        assert_eq!(db.get_highest_block().unwrap(), 104);
+
+        assert_eq!(rollback_reorg(&db, &active, 104, 6).unwrap(), 102);
+        assert_eq!(db.get_highest_block().unwrap(), 101);
+        assert_eq!(db.get_block_by_height(101).unwrap().unwrap().hash, active.get_block_hash(101).unwrap());
+        assert!(db.get_block_by_height(102).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sync_blocks() {
+        let db = database::Database::new(":memory:").unwrap();
+
+        // old chain 200..=205 already indexed, the source reorganized from 204
+        let mut old = MemorySource::starting_at(200);
+        let mut source = MemorySource::starting_at(200);
+        for height in 200..=209 {
+            let (tx, prevout) = taproot_spend(height as u8, vec![p2tr_script(1)]);
+            if height < 204 {
+                old.push_block(build_block(old.tip_hash(), height, vec![tx.clone()]), vec![prevout.clone()]);
+            } else if height <= 205 {
+                old.push_block(build_block(old.tip_hash(), height, vec![]), vec![]);
             }
-        };
+            source.push_block(build_block(source.tip_hash(), height, vec![tx]), vec![prevout]);
+        }
+        let running = AtomicBool::new(false);
+        // without workers nothing is fetched, which mustn't pass for reaching the tip
+        assert!(matches!(sync_blocks(&db, &old, 200, 205, 0, 10, &running), Err(Halt::Failed(_))));
+        assert_eq!(sync_blocks(&db, &old, 200, 205, 4, 10, &running).unwrap(), 206);
+        assert_eq!(db.get_block_by_height(205).unwrap().unwrap().hash, old.get_block_hash(205).unwrap());
+
+        // stops at the tip, past the requested end height. The node restarting while the reorg is
+        // looked into is waited out like any other unreachable node
+        let restarting = Restarting { source, height: 203, failures: AtomicU32::new(1) };
+        assert_eq!(sync_blocks(&db, &restarting, 204, 250, 4, 10, &running).unwrap(), 210);
+        assert_eq!(restarting.failures.load(Ordering::Relaxed), 0);
+        let source = restarting.source;
+        for height in 200..=209 {
+            let block = db.get_block_by_height(height).unwrap().unwrap();
+            assert_eq!(block.hash, source.get_block_hash(height).unwrap());
+            assert!(block.has_tweaks);
+            assert_eq!(db.get_tweaks_by_height(height).unwrap().len(), 1, "height {}", height);
+        }
+    }
 
-        println!("json: {:?}",block_hex);
-        let has_tweaks = chain.process_transactions(&block_hex).unwrap();
+    #[test]
+    fn test_sync_stops_when_asked() {
+        let db = database::Database::new(":memory:").unwrap();
+        let source = MemorySource::synthetic();
+
+        let stop = AtomicBool::new(true);
+        assert!(matches!(sync_blocks(&db, &source, 709632, 709635, 2, 10, &stop), Err(Halt::Interrupted)));
+        assert_eq!(db.get_highest_block().unwrap(), 0);
+
+        // an unreachable node is retried rather than given up on, until a shutdown is requested
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let rpc = RpcClient::new(startup(&temp_db("unused"), &format!("http://{}", closed.local_addr().unwrap()), Command::Sync).rpc);
+        drop(closed);
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(1500));
+                stop.store(true, Ordering::Relaxed);
+            });
+            let start = Instant::now();
+            assert!(matches!(sync_blocks(&db, &rpc, 709632, 709635, 2, 10, &stop), Err(Halt::Interrupted)));
+            assert!(start.elapsed() < Duration::from_secs(3));
+        });
+    }
+
+    fn startup(db_path: &std::path::Path, url: &str, command: Command) -> StartupParams {
+        StartupParams {
+            command,
+            db_path: db_path.to_str().unwrap().to_string(),
+            network: Network::Mainnet,
+            log_dir: std::path::PathBuf::from("logs"),
+            rpc: RpcConfig {
+                url: url.to_string(),
+                auth: RpcAuth::UserPass(String::from("user"), String::from("pass")),
+                timeout: Duration::from_secs(10),
+                retries: 0,
+            },
+            blocks_dir: None,
+            esplora_url: None,
+            poll_interval: Duration::from_secs(10),
+            reorg_depth: 100,
+            jobs: 2,
+        }
     }
-}
\ No newline at end of file
+
+    fn temp_db(name: &str) -> std::path::PathBuf {
+        let db_path = std::env::temp_dir().join(format!("tweak-indexer-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        db_path
+    }
+
+    // (height, tweak count) of every stored block from the first synthetic block
+    fn stored(db_path: &std::path::Path) -> Vec<(u32, usize)> {
+        let db = database::Database::new(db_path.to_str().unwrap()).unwrap();
+        let stored = (709632..=db.get_highest_block().unwrap())
+            .filter(|height| db.get_block_by_height(*height).unwrap().is_some())
+            .map(|height| (height, db.get_tweaks_by_height(height).unwrap().len()))
+            .collect();
+        db.close();
+        stored
+    }
+
+    #[test]
+    fn test_index_blocks_from_fake_node() {
+        let node = FakeNode::start(MemorySource::synthetic());
+        let rpc = RpcClient::new(startup(&temp_db("unused"), &node.url, Command::Sync).rpc);
+        let db_path = temp_db("fake-node");
+
+        let running = AtomicBool::new(false);
+        let range = |from, to| startup(&db_path, &node.url, Command::Range { from, to });
+        assert_eq!(index_blocks(range(709632, 709633), &rpc, &running), 0);
+        assert_eq!(stored(&db_path), vec![(709632, 1), (709633, 0)]);
+
+        // indexing an overlapping range skips what's stored and resumes after it, the last block's
+        // previous output has to be fetched with getrawtransaction. The range ends past the tip
+        assert_eq!(index_blocks(range(709632, 709640), &rpc, &running), EXIT_TIP_BELOW_RANGE);
+        assert_eq!(stored(&db_path), vec![(709632, 1), (709633, 0), (709634, 1), (709635, 3)]);
+
+        // a stray tweak in every block, which a sync keeps as the blocks' hashes still match the node
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        conn.execute("INSERT INTO tweaks (height, block_hash, tx_id, tweak) SELECT height, hash, zeroblob(32), zeroblob(33) FROM blocks", []).unwrap();
+        conn.close().unwrap();
+        assert_eq!(index_blocks(startup(&db_path, &node.url, Command::Sync), &rpc, &running), 0);
+        assert_eq!(stored(&db_path), vec![(709632, 2), (709633, 1), (709634, 2), (709635, 4)]);
+
+        // reindex drops the blocks from its height and indexes them again from the node
+        assert_eq!(index_blocks(startup(&db_path, &node.url, Command::Reindex { height: 709634 }), &rpc, &running), 0);
+        assert_eq!(stored(&db_path), vec![(709632, 2), (709633, 1), (709634, 1), (709635, 3)]);
+        let db = database::Database::new(db_path.to_str().unwrap()).unwrap();
+        let tweak = db.get_tweaks_by_height(709634).unwrap().remove(0);
+        assert_eq!(tweak.block_hash, rpc.get_block_hash(709634).unwrap());
+        assert_ne!(tweak.tweak, [0; 33]);
+        db.close();
+
+        std::fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn test_sync_waits_for_node_warm_up() {
+        let node = FakeNode::start(MemorySource::synthetic());
+        let db_path = temp_db("warm-up");
+        let startup = startup(&db_path, &node.url, Command::Sync);
+        let rpc = RpcClient::new(startup.rpc.clone());
+
+        // a starting node is retried with backoff rather than ending the sync
+        node.warm_up(1);
+        assert_eq!(index_blocks(startup, &rpc, &AtomicBool::new(false)), 0);
+        assert_eq!(stored(&db_path), vec![(709632, 1), (709633, 0), (709634, 1), (709635, 3)]);
+
+        std::fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn test_follow_fake_node() {
+        let node = FakeNode::start(MemorySource::synthetic());
+        let db_path = temp_db("follow");
+        let startup = startup(&db_path, &node.url, Command::Follow { from: None });
+        let rpc = RpcClient::new(startup.rpc.clone());
+        let db = database::Database::new(&startup.db_path).unwrap();
+        let running = AtomicBool::new(false);
+
+        assert_eq!(catch_up(&db, &rpc, &startup, &running).unwrap(), 709636);
+        assert_eq!(db.get_highest_block().unwrap(), 709635);
+
+        // a block mined while waiting wakes the long poll well before the poll interval
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(200));
+                let (tx, prevout) = taproot_spend(20, vec![p2tr_script(21)]);
+                node.push_block(vec![tx], vec![prevout]);
+            });
+            let start = Instant::now();
+            wait_for_next_block(&rpc, 709636, startup.poll_interval, &running).unwrap();
+            assert!(start.elapsed() < startup.poll_interval);
+        });
+
+        assert_eq!(catch_up(&db, &rpc, &startup, &running).unwrap(), 709637);
+        assert_eq!(db.get_tweaks_by_height(709636).unwrap().len(), 1);
+        db.close();
+
+        std::fs::remove_file(&db_path).unwrap();
+    }
+}
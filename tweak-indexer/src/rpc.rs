@@ -0,0 +1,228 @@
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::source::with_retries;
+
+// Bitcoin Core RPC error codes we need to tell apart (see src/rpc/protocol.h)
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+const RPC_INVALID_PARAMETER: i64 = -8;
+const RPC_IN_WARMUP: i64 = -28;
+
+#[derive(Debug, Clone)]
+pub enum RpcAuth {
+    Cookie(PathBuf),
+    UserPass(String, String),
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    pub url: String,
+    pub auth: RpcAuth,
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    Transport(String),
+    Http(u16),
+    Rpc { code: i64, message: String },
+    Cookie(std::io::Error),
+    Json(String),
+}
+impl std::error::Error for RpcError {}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RpcError::Transport(err) => write!(f, "RPC transport error: {}", err),
+            RpcError::Http(status) => write!(f, "RPC HTTP status {}", status),
+            RpcError::Rpc { code, message } => write!(f, "RPC error {}: {}", code, message),
+            RpcError::Cookie(err) => write!(f, "Unable to read RPC cookie file: {}", err),
+            RpcError::Json(err) => write!(f, "Unable to parse RPC response: {}", err),
+        }
+    }
+}
+
+impl RpcError {
+    /// `getblockhash` was asked for a height above the node's tip
+    pub fn is_height_out_of_range(&self) -> bool {
+        matches!(self, RpcError::Rpc { code: RPC_INVALID_PARAMETER, .. })
+    }
+
+    /// Unknown block hash or txid (`getblock`, `getrawtransaction`)
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, RpcError::Rpc { code: RPC_INVALID_ADDRESS_OR_KEY, .. })
+    }
+
+    /// The node is down, restarting or too busy to answer, worth retrying later. While bitcoind
+    /// is stopped its cookie file is gone, and while starting it answers with RPC_IN_WARMUP. A full
+    /// work queue is answered with a bare 503
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            RpcError::Transport(_) | RpcError::Cookie(_) | RpcError::Http(503) | RpcError::Rpc { code: RPC_IN_WARMUP, .. }
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcErrorObject>,
+}
+
+#[derive(Deserialize)]
+struct RpcErrorObject {
+    code: i64,
+    message: String,
+}
+
+pub struct RpcClient {
+    agent: ureq::Agent,
+    url: String,
+    auth: RpcAuth,
+    timeout: Duration,
+    retries: u32,
+    stop: Option<&'static AtomicBool>,
+}
+
+impl RpcClient {
+    pub fn new(config: RpcConfig) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(config.timeout)
+            .build();
+        Self { agent, url: config.url, auth: config.auth, timeout: config.timeout, retries: config.retries, stop: None }
+    }
+
+    /// Give up retrying a call once `stop` is set
+    pub fn stop_on(self, stop: &'static AtomicBool) -> Self {
+        Self { stop: Some(stop), ..self }
+    }
+
+    // Cookie is re-read on every call since bitcoind rewrites it on restart
+    fn authorization(&self) -> Result<String, RpcError> {
+        let credentials = match &self.auth {
+            RpcAuth::Cookie(path) => std::fs::read_to_string(path)
+                .map_err(RpcError::Cookie)?
+                .trim()
+                .to_string(),
+            RpcAuth::UserPass(user, pass) => format!("{}:{}", user, pass),
+        };
+        Ok(format!("Basic {}", BASE64.encode(credentials)))
+    }
+
+    fn send<T: DeserializeOwned>(&self, method: &str, params: &[Value], timeout: Duration) -> Result<T, RpcError> {
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": "tweak-indexer",
+            "method": method,
+            "params": params,
+        });
+
+        let response = match self.agent.post(&self.url)
+            .timeout(timeout)
+            .set("Authorization", &self.authorization()?)
+            .send_json(request)
+        {
+            Ok(response) => response,
+            // bitcoind reports RPC errors with a non 200 status but still sends a JSON body
+            Err(ureq::Error::Status(status, response)) => {
+                return match response.into_json::<RpcResponse<Value>>() {
+                    Ok(RpcResponse { error: Some(err), .. }) => Err(RpcError::Rpc { code: err.code, message: err.message }),
+                    _ => Err(RpcError::Http(status)),
+                };
+            }
+            Err(err) => return Err(RpcError::Transport(err.to_string())),
+        };
+
+        let response: RpcResponse<T> = response.into_json()
+            .map_err(|e| RpcError::Json(e.to_string()))?;
+
+        match (response.result, response.error) {
+            (_, Some(err)) => Err(RpcError::Rpc { code: err.code, message: err.message }),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(RpcError::Json(format!("{} returned no result", method))),
+        }
+    }
+
+    /// Call `method`, retrying while the node is unreachable (see `RpcError::is_transient`)
+    pub fn call<T: DeserializeOwned>(&self, method: &str, params: &[Value]) -> Result<T, RpcError> {
+        self.call_with_timeout(method, params, self.timeout)
+    }
+
+    /// Call a method that holds the request open for up to `wait` (e.g. `waitforblockheight`)
+    pub fn long_poll<T: DeserializeOwned>(&self, method: &str, params: &[Value], wait: Duration) -> Result<T, RpcError> {
+        self.call_with_timeout(method, params, wait + self.timeout)
+    }
+
+    fn call_with_timeout<T: DeserializeOwned>(&self, method: &str, params: &[Value], timeout: Duration) -> Result<T, RpcError> {
+        with_retries(&format!("RPC {}", method), self.retries, self.stop, RpcError::is_transient, || self.send(method, params, timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // Answer a single HTTP request with the given status line and JSON body
+    fn serve_once(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).unwrap();
+            let response = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        url
+    }
+
+    fn client(url: String) -> RpcClient {
+        RpcClient::new(RpcConfig {
+            url,
+            auth: RpcAuth::UserPass(String::from("user"), String::from("pass")),
+            timeout: Duration::from_secs(5),
+            retries: 0,
+        })
+    }
+
+    #[test]
+    fn test_rpc_result_and_errors() {
+        let url = serve_once("200 OK", r#"{"result":840000,"error":null,"id":"tweak-indexer"}"#);
+        assert_eq!(client(url).call::<u32>("getblockcount", &[]).unwrap(), 840000);
+
+        let url = serve_once("500 Internal Server Error", r#"{"result":null,"error":{"code":-8,"message":"Block height out of range"},"id":"tweak-indexer"}"#);
+        let err = client(url).call::<String>("getblockhash", &[json!(99999999)]).unwrap_err();
+        assert!(err.is_height_out_of_range());
+
+        let url = serve_once("401 Unauthorized", "");
+        assert!(matches!(client(url).call::<u32>("getblockcount", &[]), Err(RpcError::Http(401))));
+    }
+
+    #[test]
+    fn test_retries_end_on_stop() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", closed.local_addr().unwrap());
+        drop(closed);
+        let rpc = RpcClient { retries: 10, ..client(url) }.stop_on(&STOP);
+        std::thread::spawn(|| {
+            std::thread::sleep(Duration::from_millis(300));
+            STOP.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        // without the stop the retries would wait 55 seconds
+        let start = std::time::Instant::now();
+        assert!(matches!(rpc.call::<u32>("getblockcount", &[]), Err(RpcError::Transport(_))));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use crate::blockfiles::BlockFileError;
+use crate::chain::PreviousScript;
+use crate::esplora::EsploraError;
+use crate::rpc::RpcError;
+
+#[derive(Debug)]
+pub enum SourceError {
+    HeightOutOfRange(u32),
+    NotFound(String),
+    Rpc(RpcError),
+    BlockFile(BlockFileError),
+    Esplora(EsploraError),
+    // asked for a transaction by txid, got one with another
+    WrongTransaction { txid: String, got: String },
+}
+impl std::error::Error for SourceError {}
+
+impl std::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SourceError::HeightOutOfRange(height) => write!(f, "Block height {} out of range", height),
+            SourceError::NotFound(item) => write!(f, "Not found: {}", item),
+            SourceError::Rpc(err) => write!(f, "{}", err),
+            SourceError::BlockFile(err) => write!(f, "{}", err),
+            SourceError::Esplora(err) => write!(f, "{}", err),
+            SourceError::WrongTransaction { txid, got } => write!(f, "Asked for transaction {}, got {}", txid, got),
+        }
+    }
+}
+
+impl SourceError {
+    /// The source could not be reached, indexing can carry on once it is back
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SourceError::Rpc(err) => err.is_transient(),
+            SourceError::Esplora(err) => err.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+/// How often a sleeping or waiting indexer checks whether it should shut down
+pub const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sleep for `duration`, returning false as soon as a shutdown is requested
+pub fn sleep_unless_stopped(stop: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        sleep(remaining.min(STOP_CHECK_INTERVAL));
+    }
+    false
+}
+
+/// Run `request`, retrying failures `is_transient` accepts up to `retries` times, waiting a second
+/// longer before each. A shutdown requested through `stop` ends the retries with the last error,
+/// left to the caller to report. `what` names the request in the log
+pub fn with_retries<T, E: Display>(what: &str, retries: u32, stop: Option<&AtomicBool>, is_transient: fn(&E) -> bool, mut request: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    let never = AtomicBool::new(false);
+    let stop = stop.unwrap_or(&never);
+    let mut attempt = 0;
+    loop {
+        match request() {
+            Err(err) if is_transient(&err) && attempt < retries => {
+                attempt += 1;
+                warn!("{} failed (attempt {}/{}): {}", what, attempt, retries, err);
+                if !sleep_unless_stopped(stop, Duration::from_secs(attempt as u64)) {
+                    return Err(err);
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+impl From<RpcError> for SourceError {
+    fn from(err: RpcError) -> Self {
+        SourceError::Rpc(err)
+    }
+}
+
+/// Where the indexer gets blocks and the previous outputs they spend from.
+///
+/// Block hashes and txids are passed as hex strings and blocks/transactions are
+/// returned consensus serialized as hex, the same shape `getblock <hash> 0` and
+/// `getrawtransaction` return. Sources are shared by the sync workers so must be `Sync`.
+pub trait ChainSource: Sync {
+    fn get_block_count(&self) -> Result<u32, SourceError>;
+
+    /// Errors with `SourceError::HeightOutOfRange` once past the tip
+    fn get_block_hash(&self, height: u32) -> Result<String, SourceError>;
+
+    fn get_block(&self, block_hash: &str) -> Result<String, SourceError>;
+
+    /// Previous output script of every non coinbase input in the block
+    fn get_previous_scripts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, SourceError>;
+
+    fn get_transaction(&self, txid: &str) -> Result<String, SourceError>;
+
+    /// Block until the tip reaches `height` or `timeout` passes, returning the tip height.
+    /// Sources without new block notifications return `None` and the caller polls instead
+    fn wait_for_block(&self, _height: u32, _timeout: Duration) -> Result<Option<u32>, SourceError> {
+        Ok(None)
+    }
+}
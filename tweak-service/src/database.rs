@@ -1,6 +1,9 @@
 
 use rusqlite::{params, Connection, Result};
 use serde::{Deserialize, Serialize};
+use tweak_common::network::Network;
+use tweak_common::schema::{self, to_blob, SchemaError};
+use tweak_common::wire;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Tweak {
@@ -9,21 +12,117 @@ pub struct Tweak {
     pub tweak: String,
 }
 
+// A stored block with its tweaks, empty if it has none
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BlockTweaks {
+    pub height: u32,
+    pub block_hash: String,
+    pub tweaks: Vec<Tweak>,
+}
+
+impl BlockTweaks {
+    // The block in the binary format, `None` if a stored hash or tweak has the wrong length
+    pub fn to_wire(&self) -> Option<wire::BlockTweaks> {
+        let tweaks = self.tweaks.iter()
+            .map(|tweak| hex::decode(&tweak.tweak).ok()?.try_into().ok())
+            .collect::<Option<_>>()?;
+        Some(wire::BlockTweaks {
+            height: self.height,
+            block_hash: hex::decode(&self.block_hash).ok()?.try_into().ok()?,
+            tweaks,
+        })
+    }
+}
+
+// A P2TR output of a transaction with a tweak
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Output {
+    pub tx_id: String,
+    pub vout: u32,
+    // x-only output key
+    pub pubkey: String,
+    // sats
+    pub value: u64,
+}
+
+// A block's taproot output filter, see `Chain::taproot_filter` in tweak-indexer
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Filter {
+    pub height: u32,
+    pub block_hash: String,
+    // hex, `None` if the block was indexed before filters were built
+    pub filter: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TweakMetrics {
     pub block_hash: String,
     pub tweak_count: u32,
 }
 
-// Function to fetch tweaks from SQLite
-pub fn fetch_tweaks(block_hash: String, db_path: &String) -> Result<Vec<Tweak>> {
+// Bring the database up to the current schema, refusing one written by a newer binary
+// or indexed from a different network
+pub fn migrate(db_path: &String, network: Network) -> std::result::Result<(), SchemaError> {
+    let mut conn = Connection::open(db_path)?;
+    schema::migrate(&mut conn)?;
+    schema::check_network(&conn, network)
+}
+
+fn get_hex(row: &rusqlite::Row, index: usize) -> Result<String> {
+    row.get::<_, Vec<u8>>(index).map(hex::encode)
+}
+
+// Height of a stored block, `None` if it hasn't been indexed
+pub fn get_block_height(block_hash: &str, db_path: &String) -> Result<Option<u32>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT height FROM blocks WHERE hash = ?1")?;
+    let mut heights = stmt.query_map(params![to_blob(block_hash)?], |row| row.get(0))?;
+    heights.next().transpose()
+}
+
+// Function to fetch tweaks from SQLite. With a `dust_limit` (sats), tweaks whose transaction has
+// no taproot output of at least that value are left out. Tweaks indexed before output values were
+// stored are always returned, so a client never misses a payment
+pub fn fetch_tweaks(block_hash: String, dust_limit: Option<u64>, db_path: &String) -> Result<Vec<Tweak>> {
+    match get_block_height(&block_hash, db_path)? {
+        Some(height) => fetch_tweaks_by_height(height, dust_limit, db_path),
+        None => Ok(vec![]),
+    }
+}
+
+pub fn fetch_tweaks_by_height(height: u32, dust_limit: Option<u64>, db_path: &String) -> Result<Vec<Tweak>> {
+    let conn = Connection::open(db_path)?;
+    tweaks_at(&conn, height, dust_limit)
+}
+
+// The block stored at `height` with its tweaks, `None` if it hasn't been indexed
+pub fn fetch_block_tweaks(height: u32, dust_limit: Option<u64>, db_path: &String) -> Result<Option<BlockTweaks>> {
+    Ok(fetch_tweaks_in_range(height, height, dust_limit, db_path)?.pop())
+}
+
+// Every stored block in `from..=to` in height order, with its tweaks
+pub fn fetch_tweaks_in_range(from: u32, to: u32, dust_limit: Option<u64>, db_path: &String) -> Result<Vec<BlockTweaks>> {
     let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare("SELECT block_hash, tx_id, tweak FROM tweaks WHERE block_hash = ?1")?;
-    let tweaks_iter = stmt.query_map(params![block_hash], |row| {
+    let mut stmt = conn.prepare("SELECT height, hash FROM blocks WHERE height BETWEEN ?1 AND ?2 ORDER BY height")?;
+    let blocks = stmt.query_map(params![from, to], |row| Ok((row.get(0)?, get_hex(row, 1)?)))?
+        .collect::<Result<Vec<(u32, String)>>>()?;
+
+    blocks.into_iter()
+        .map(|(height, block_hash)| Ok(BlockTweaks { height, block_hash, tweaks: tweaks_at(&conn, height, dust_limit)? }))
+        .collect()
+}
+
+fn tweaks_at(conn: &Connection, height: u32, dust_limit: Option<u64>) -> Result<Vec<Tweak>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT block_hash, tx_id, tweak FROM tweaks
+         WHERE height = ?1 AND (?2 IS NULL OR max_value IS NULL OR max_value >= ?2)",
+    )?;
+    let dust_limit = dust_limit.map(|limit| limit.min(i64::MAX as u64) as i64);
+    let tweaks_iter = stmt.query_map(params![height, dust_limit], |row| {
         Ok(Tweak {
-            block_hash: row.get(0)?,
-            tx_id: row.get(1)?,
-            tweak: row.get(2)?,
+            block_hash: get_hex(row, 0)?,
+            tx_id: get_hex(row, 1)?,
+            tweak: get_hex(row, 2)?,
         })
     })?;
     
@@ -31,12 +130,54 @@ pub fn fetch_tweaks(block_hash: String, db_path: &String) -> Result<Vec<Tweak>>
     Ok(tweaks)
 }
 
+pub fn fetch_outputs(block_hash: &str, db_path: &String) -> Result<Vec<Output>> {
+    match get_block_height(block_hash, db_path)? {
+        Some(height) => fetch_outputs_by_height(height, db_path),
+        None => Ok(vec![]),
+    }
+}
+
+pub fn fetch_outputs_by_height(height: u32, db_path: &String) -> Result<Vec<Output>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT tx_id, vout, pubkey, value FROM outputs WHERE height = ?1 ORDER BY tx_id, vout")?;
+    let outputs_iter = stmt.query_map(params![height], |row| {
+        Ok(Output {
+            tx_id: get_hex(row, 0)?,
+            vout: row.get(1)?,
+            pubkey: get_hex(row, 2)?,
+            value: row.get::<_, i64>(3)? as u64,
+        })
+    })?;
+
+    outputs_iter.collect()
+}
+
+pub fn fetch_filter(block_hash: &str, db_path: &String) -> Result<Option<Filter>> {
+    match get_block_height(block_hash, db_path)? {
+        Some(height) => fetch_filter_by_height(height, db_path),
+        None => Ok(None),
+    }
+}
+
+pub fn fetch_filter_by_height(height: u32, db_path: &String) -> Result<Option<Filter>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT height, hash, filter FROM blocks WHERE height = ?1")?;
+    let mut filters = stmt.query_map(params![height], |row| {
+        Ok(Filter {
+            height: row.get(0)?,
+            block_hash: get_hex(row, 1)?,
+            filter: row.get::<_, Option<Vec<u8>>>(2)?.map(hex::encode),
+        })
+    })?;
+    filters.next().transpose()
+}
+
 pub fn get_tweak_metrics(db_path: &String) -> Result<Vec<TweakMetrics>> {
     let conn = Connection::open(db_path)?;
     let mut stmt = conn.prepare("SELECT block_hash, count(tweak) FROM tweaks GROUP BY block_hash order by count(tweak) desc")?;
     let tweaks_iter = stmt.query_map(params![], |row| {
         Ok(TweakMetrics {
-            block_hash: row.get(0)?,
+            block_hash: get_hex(row, 0)?,
             tweak_count: row.get(1)?,
         })
     })?;
@@ -51,4 +192,69 @@ pub fn get_highest_block(db_path: &String) -> Result<u32> {
     let highest_block: Option<u32> = stmt.query_row([], |row| row.get(0)).ok();
 
     Ok(highest_block.unwrap_or(0))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Migrated in-memory database, shared by every connection opened on the returned path for as long
+    // as the returned connection stays open
+    pub fn memory_db(name: &str) -> (Connection, String) {
+        let db_path = format!("file:{}?mode=memory&cache=shared", name);
+        let mut conn = Connection::open(&db_path).unwrap();
+        schema::migrate(&mut conn).unwrap();
+        (conn, db_path)
+    }
+
+    pub fn block_hash(height: u32) -> String {
+        format!("{:064x}", height)
+    }
+
+    // A block at `height` with a tweak per entry of `max_values`
+    pub fn insert_block(conn: &Connection, height: u32, max_values: &[Option<i64>]) {
+        let hash = to_blob(&block_hash(height)).unwrap();
+        conn.execute("INSERT INTO blocks (height, hash, has_tweaks) VALUES (?1, ?2, ?3)", params![height, hash, !max_values.is_empty()]).unwrap();
+        for (index, max_value) in max_values.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO tweaks (height, block_hash, tx_id, tweak, max_value) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![height, hash, [index as u8; 32], [2u8; 33], max_value],
+            ).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fetch_tweaks_in_range() {
+        let (conn, db_path) = memory_db("fetch_tweaks_in_range");
+        insert_block(&conn, 100, &[Some(1000), Some(1000)]);
+        insert_block(&conn, 101, &[]);
+        insert_block(&conn, 103, &[Some(1000)]);
+
+        // blocks without tweaks are included, ones not indexed aren't
+        let blocks = fetch_tweaks_in_range(100, 103, None, &db_path).unwrap();
+        let summary: Vec<_> = blocks.iter().map(|block| (block.height, block.block_hash.clone(), block.tweaks.len())).collect();
+        assert_eq!(summary, vec![(100, block_hash(100), 2), (101, block_hash(101), 0), (103, block_hash(103), 1)]);
+        assert_eq!(blocks[0].tweaks[1].tx_id, hex::encode([1u8; 32]));
+        assert!(fetch_tweaks_in_range(104, 200, None, &db_path).unwrap().is_empty());
+
+        assert!(fetch_block_tweaks(101, None, &db_path).unwrap().unwrap().tweaks.is_empty());
+        assert!(fetch_block_tweaks(102, None, &db_path).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_tweaks_at_dust_limit() {
+        let (conn, _) = memory_db("tweaks_at_dust_limit");
+        // the last was indexed before output values were stored
+        insert_block(&conn, 100, &[Some(500), Some(1000), None]);
+
+        let tx_ids = |dust_limit| -> Vec<String> {
+            tweaks_at(&conn, 100, dust_limit).unwrap().into_iter().map(|tweak| tweak.tx_id).collect()
+        };
+        let tx_id = |index: u8| hex::encode([index; 32]);
+        assert_eq!(tx_ids(None), vec![tx_id(0), tx_id(1), tx_id(2)]);
+        assert_eq!(tx_ids(Some(500)), vec![tx_id(0), tx_id(1), tx_id(2)]);
+        assert_eq!(tx_ids(Some(1000)), vec![tx_id(1), tx_id(2)]);
+        assert_eq!(tx_ids(Some(1001)), vec![tx_id(2)]);
+        // beyond what SQLite's INTEGER holds
+        assert_eq!(tx_ids(Some(u64::MAX)), vec![tx_id(2)]);
+    }
+}
@@ -1,17 +1,189 @@
 
+use clap::Parser;
+use serde::{Deserialize, Serialize};
 use warp::{Filter, Rejection, Reply};
+use warp::http::{header, StatusCode};
 use warp::reply::{html,json};
 use rusqlite::Result;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use tweak_common::config::Config;
+use tweak_common::network::Network;
+use tweak_common::wire;
 
 mod database;
 
-async fn get_tweaks(block_hash: String, db_path: String) -> Result<impl Reply, Rejection> {
-    match database::fetch_tweaks(block_hash, &db_path) {
-        Ok(tweaks) => Ok(json(&tweaks)),
+// Most blocks a single `/tweaks?from=&to=` response covers
+const MAX_BLOCKS_PER_PAGE: u32 = 1000;
+
+#[derive(Parser)]
+struct Cli {
+    /// TOML config file shared with tweak-indexer, the flags below override it (default: tweak.toml if it exists)
+    #[arg(long, env = "TWEAK_CONFIG")]
+    config: Option<PathBuf>,
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+    /// SQLite database written by tweak-indexer (default: blocks.db)
+    #[arg(long, env = "TWEAK_DB")]
+    db: Option<String>,
+    /// Network the database must have been indexed from (default: mainnet)
+    #[arg(long, env = "TWEAK_NETWORK")]
+    network: Option<Network>,
+    /// Address to listen on (default: 0.0.0.0)
+    #[arg(long, env = "TWEAK_BIND")]
+    bind: Option<IpAddr>,
+    /// (default: 3030)
+    #[arg(long, env = "TWEAK_PORT")]
+    port: Option<u16>,
+}
+
+// The config file with the flags given on the command line applied on top
+fn load_config(cli: &Cli) -> Config {
+    let mut config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(db) = &cli.db { config.db = db.clone(); }
+    if let Some(network) = cli.network { config.network = network; }
+    if let Some(bind) = cli.bind { config.service.bind = bind; }
+    if let Some(port) = cli.port { config.service.port = port; }
+    config
+}
+
+// Whether the client asked for the binary format of `tweak_common::wire` instead of JSON
+fn wants_binary(accept: &Option<String>) -> bool {
+    accept.as_deref().is_some_and(|accept| {
+        accept.split(',').any(|media_type| media_type.trim().starts_with(wire::CONTENT_TYPE))
+    })
+}
+
+// Encode `blocks` in the binary format, with the `from` of the next page of a range in `X-Next-From`
+fn binary_reply(blocks: Result<Vec<database::BlockTweaks>>, next: Option<u32>) -> warp::reply::Response {
+    let blocks = match blocks {
+        Ok(blocks) => blocks,
+        Err(err) => return warp::reply::with_status(json(&err.to_string()), StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+    let mut body = Vec::new();
+    for block in &blocks {
+        match block.to_wire() {
+            Some(block) => block.encode(&mut body),
+            None => {
+                let err = format!("block {} is stored malformed", block.block_hash);
+                return warp::reply::with_status(json(&err), StatusCode::INTERNAL_SERVER_ERROR).into_response();
+            }
+        }
+    }
+
+    let mut response = warp::http::Response::builder().header(header::CONTENT_TYPE, wire::CONTENT_TYPE);
+    if let Some(next) = next {
+        response = response.header("X-Next-From", next);
+    }
+    response.body(body.into()).expect("valid response headers")
+}
+
+#[derive(Deserialize)]
+struct TweaksQuery {
+    // sats, leave out tweaks whose taproot outputs are all below it
+    dust_limit: Option<u64>,
+}
+
+async fn get_tweaks(block_hash: String, query: TweaksQuery, accept: Option<String>, db_path: String) -> Result<warp::reply::Response, Rejection> {
+    if wants_binary(&accept) {
+        return match database::get_block_height(&block_hash, &db_path) {
+            Ok(Some(height)) => Ok(binary_reply(database::fetch_tweaks_in_range(height, height, query.dust_limit, &db_path), None)),
+            Ok(None) => Ok(not_indexed()),
+            Err(err) => Ok(binary_reply(Err(err), None)),
+        };
+    }
+    match database::fetch_tweaks(block_hash, query.dust_limit, &db_path) {
+        Ok(tweaks) => Ok(json(&tweaks).into_response()),
+        Err(err) => Ok(json(&err.to_string()).into_response()),
+    }
+}
+
+async fn get_tweaks_by_height(height: u32, query: TweaksQuery, accept: Option<String>, db_path: String) -> Result<warp::reply::Response, Rejection> {
+    match database::fetch_block_tweaks(height, query.dust_limit, &db_path) {
+        Ok(None) => Ok(not_indexed()),
+        Ok(Some(block)) if wants_binary(&accept) => Ok(binary_reply(Ok(vec![block]), None)),
+        Ok(Some(block)) => Ok(json(&block).into_response()),
+        Err(err) if wants_binary(&accept) => Ok(binary_reply(Err(err), None)),
+        Err(err) => Ok(json(&err.to_string()).into_response()),
+    }
+}
+
+#[derive(Deserialize)]
+struct RangeQuery {
+    from: u32,
+    to: u32,
+    // blocks per page, at most MAX_BLOCKS_PER_PAGE
+    limit: Option<u32>,
+    dust_limit: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct TweaksPage {
+    blocks: Vec<database::BlockTweaks>,
+    // `from` of the next page, absent on the last one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<u32>,
+}
+
+async fn get_tweaks_in_range(query: RangeQuery, accept: Option<String>, db_path: String) -> Result<warp::reply::Response, Rejection> {
+    if query.from > query.to {
+        return Ok(warp::reply::with_status(json(&"from must not be above to"), StatusCode::BAD_REQUEST).into_response());
+    }
+    let limit = query.limit.unwrap_or(MAX_BLOCKS_PER_PAGE).clamp(1, MAX_BLOCKS_PER_PAGE);
+    let page_end = query.to.min(query.from.saturating_add(limit - 1));
+    let next = if page_end < query.to { Some(page_end + 1) } else { None };
+
+    if wants_binary(&accept) {
+        return Ok(binary_reply(database::fetch_tweaks_in_range(query.from, page_end, query.dust_limit, &db_path), next));
+    }
+    match database::fetch_tweaks_in_range(query.from, page_end, query.dust_limit, &db_path) {
+        Ok(blocks) => Ok(json(&TweaksPage { blocks, next }).into_response()),
+        Err(err) => Ok(json(&err.to_string()).into_response()),
+    }
+}
+
+async fn get_outputs(block_hash: String, db_path: String) -> Result<impl Reply, Rejection> {
+    match database::fetch_outputs(&block_hash, &db_path) {
+        Ok(outputs) => Ok(json(&outputs)),
+        Err(err) => Ok(json(&err.to_string())),
+    }
+}
+
+async fn get_outputs_by_height(height: u32, db_path: String) -> Result<impl Reply, Rejection> {
+    match database::fetch_outputs_by_height(height, &db_path) {
+        Ok(outputs) => Ok(json(&outputs)),
         Err(err) => Ok(json(&err.to_string())),
     }
 }
 
+// 404 for a block the indexer hasn't reached, so it isn't mistaken for one without tweaks
+fn not_indexed() -> warp::reply::Response {
+    warp::reply::with_status(json(&"block not indexed"), StatusCode::NOT_FOUND).into_response()
+}
+
+fn filter_reply(filter: Result<Option<database::Filter>>) -> warp::reply::Response {
+    match filter {
+        Ok(Some(filter)) => json(&filter).into_response(),
+        Ok(None) => not_indexed(),
+        Err(err) => json(&err.to_string()).into_response(),
+    }
+}
+
+async fn get_filter(block_hash: String, db_path: String) -> Result<warp::reply::Response, Rejection> {
+    Ok(filter_reply(database::fetch_filter(&block_hash, &db_path)))
+}
+
+async fn get_filter_by_height(height: u32, db_path: String) -> Result<warp::reply::Response, Rejection> {
+    Ok(filter_reply(database::fetch_filter_by_height(height, &db_path)))
+}
+
 async fn get_tweak_metrics(db_path: String) -> Result<impl Reply, Rejection> {
     match database::get_tweak_metrics(&db_path) {
         Ok(tweaks) => {
@@ -44,10 +216,46 @@ fn with_db_path(db_path: String) -> impl Filter<Extract = (String,), Error = std
 
 #[tokio::main]
 async fn main() {
-    let db_path = String::from("blocks.db");
+    let cli = Cli::parse();
+    let config = load_config(&cli);
+    if cli.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+
+    let db_path = config.db;
+    if let Err(err) = database::migrate(&db_path, config.network) {
+        eprintln!("Not able to open database {}: {}", db_path, err);
+        std::process::exit(1);
+    }
+
     let tweaks_route = warp::path!("tweaks" / String)
+    .and(warp::query::<TweaksQuery>())
+    .and(warp::header::optional::<String>("accept"))
     .and(with_db_path(db_path.clone()))
     .and_then(get_tweaks);
+    let tweaks_by_height_route = warp::path!("tweaks" / "height" / u32)
+    .and(warp::query::<TweaksQuery>())
+    .and(warp::header::optional::<String>("accept"))
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_tweaks_by_height);
+    let tweaks_range_route = warp::path!("tweaks")
+    .and(warp::query::<RangeQuery>())
+    .and(warp::header::optional::<String>("accept"))
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_tweaks_in_range);
+    let filter_route = warp::path!("filter" / String)
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_filter);
+    let filter_by_height_route = warp::path!("filter" / "height" / u32)
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_filter_by_height);
+    let outputs_route = warp::path!("outputs" / String)
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_outputs);
+    let outputs_by_height_route = warp::path!("outputs" / "height" / u32)
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_outputs_by_height);
     let tweak_metrics = warp::path!("block_stats")
     .and(with_db_path(db_path.clone()))
     .and_then(get_tweak_metrics);
@@ -56,8 +264,69 @@ async fn main() {
     .and_then(get_status);
 
     let routes = tweaks_route
+    .or(tweaks_by_height_route)
+    .or(tweaks_range_route)
+    .or(filter_route)
+    .or(filter_by_height_route)
+    .or(outputs_route)
+    .or(outputs_by_height_route)
     .or(status_route)
     .or(tweak_metrics);
 
-    warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
-}
\ No newline at end of file
+    warp::serve(routes).run(SocketAddr::new(config.service.bind, config.service.port)).await;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::{block_hash, insert_block, memory_db};
+
+    async fn range(from: u32, to: u32, limit: u32, accept: Option<&str>, db_path: &str) -> warp::reply::Response {
+        let query = RangeQuery { from, to, limit: Some(limit), dust_limit: None };
+        get_tweaks_in_range(query, accept.map(String::from), db_path.to_string()).await.unwrap()
+    }
+
+    async fn body(response: warp::reply::Response) -> Vec<u8> {
+        warp::hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_range_pages() {
+        let (conn, db_path) = memory_db("range_pages");
+        for height in 100..=103 {
+            insert_block(&conn, height, &[Some(1000)]);
+        }
+
+        // a page ending before `to` points at the next one, in the header for the binary format
+        let response = range(100, 103, 2, Some(wire::CONTENT_TYPE), &db_path).await;
+        assert_eq!(response.headers()["X-Next-From"], "102");
+        let blocks: Vec<_> = wire::Decoder::new(body(response).await.as_slice()).map(|block| block.unwrap().height).collect();
+        assert_eq!(blocks, vec![100, 101]);
+
+        let page: serde_json::Value = serde_json::from_slice(&body(range(100, 103, 2, None, &db_path).await).await).unwrap();
+        assert_eq!((page["blocks"].as_array().unwrap().len(), &page["next"]), (2, &serde_json::json!(102)));
+
+        // the last page, whose end is exactly `to`, has no next
+        let response = range(102, 103, 2, Some(wire::CONTENT_TYPE), &db_path).await;
+        assert!(response.headers().get("X-Next-From").is_none());
+        let page: serde_json::Value = serde_json::from_slice(&body(range(102, 103, 2, None, &db_path).await).await).unwrap();
+        assert_eq!(page["blocks"][1]["block_hash"], block_hash(103));
+        assert!(page.get("next").is_none());
+
+        let response = get_tweaks_by_height(104, TweaksQuery { dust_limit: None }, None, db_path.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_tweaks_by_hash() {
+        let (conn, db_path) = memory_db("tweaks_by_hash");
+        insert_block(&conn, 100, &[Some(1000)]);
+        let tweaks = |hash: String| get_tweaks(hash, TweaksQuery { dust_limit: None }, Some(wire::CONTENT_TYPE.to_string()), db_path.clone());
+
+        let response = tweaks(block_hash(100)).await.unwrap();
+        let blocks: Vec<_> = wire::Decoder::new(body(response).await.as_slice()).map(|block| block.unwrap().tweaks.len()).collect();
+        assert_eq!(blocks, vec![1]);
+
+        // an unknown hash is not indexed rather than a block without tweaks
+        assert_eq!(tweaks(block_hash(101)).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}